@@ -0,0 +1,101 @@
+use std::fmt;
+use crate::lexer::Span;
+
+// Error codes, one per kind of problem the compiler can report.
+pub const UNEXPECTED_TOKEN: &str = "E0001";
+pub const INVALID_TYPE: &str = "E0002";
+pub const MISSING_RETURN: &str = "E0003";
+// E0004 was reported for unsupported features and is retired: codes are
+// never reused, so that they keep their meaning for users.
+pub const INVALID_CHARACTER: &str = "E0005";
+pub const ARGUMENT_COUNT: &str = "E0006";
+pub const REDECLARATION: &str = "E0007";
+pub const UNDECLARED: &str = "E0008";
+pub const TYPE_MISMATCH: &str = "E0009";
+pub const ASSIGN_TO_CONST: &str = "E0010";
+pub const INVALID_LITERAL: &str = "E0011";
+pub const UNTERMINATED_COMMENT: &str = "E0012";
+
+// Warning codes.
+pub const UNREACHABLE_CODE: &str = "W0001";
+pub const SHADOWING: &str = "W0002";
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+/// A secondary location attached to a diagnostic, e.g. "first declared here".
+#[derive(PartialEq, Debug, Clone)]
+pub struct Label {
+    pub span: Span,
+    pub message: String,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    pub span: Span,
+    pub labels: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(severity: Severity, code: &'static str, message: String, span: Span) -> Self {
+        Diagnostic {
+            severity,
+            code,
+            message,
+            span,
+            labels: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn error(code: &'static str, message: String, span: Span) -> Self {
+        Diagnostic::new(Severity::Error, code, message, span)
+    }
+
+    pub fn warning(code: &'static str, message: String, span: Span) -> Self {
+        Diagnostic::new(Severity::Warning, code, message, span)
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    pub fn with_label(mut self, span: Span, message: &str) -> Self {
+        self.labels.push(Label { span, message: message.to_string() });
+        self
+    }
+
+    pub fn with_note(mut self, note: &str) -> Self {
+        self.notes.push(note.to_string());
+        self
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}[{}]: {}\n --> {}", self.severity, self.code, self.message, self.span)?;
+        for label in &self.labels {
+            write!(f, "\n --> {}: {}", label.span, label.message)?;
+        }
+        for note in &self.notes {
+            write!(f, "\n  = note: {}", note)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+use crate::diagnostic::{self, Diagnostic};
+use crate::parser::ASTNode;
+use crate::types::Type;
+
+/// True when control can never fall through `node`: every path through it
+/// ends in a `return` or loops forever.
+pub fn always_returns(node: &ASTNode) -> bool {
+    match node {
+        ASTNode::ReturnStmt(..) => true,
+        ASTNode::Block(instructions, _) => instructions.iter().any(always_returns),
+        ASTNode::IfStmt { if_branch, else_branch: Some(else_branch), .. } => {
+            always_returns(if_branch) && always_returns(else_branch)
+        }
+        // The language has no `break`, so `for (;;)`, `while (1)` and
+        // `do ... while (1)` never exit.
+        ASTNode::ForStmt { condition: None, .. } => true,
+        ASTNode::WhileStmt { condition, .. } => is_always_true(condition),
+        ASTNode::DoWhileStmt { body, condition, .. } => always_returns(body) || is_always_true(condition),
+        _ => false,
+    }
+}
+
+fn is_always_true(condition: &ASTNode) -> bool {
+    matches!(condition, ASTNode::IntLiteral(value, _) if *value != 0)
+}
+
+/// Reports non-void functions where control can reach the closing brace
+/// without going through a `return`. Functions with a statement that failed
+/// to parse are skipped, as that statement may well have been the return.
+pub fn check_returns(node: &ASTNode, diagnostics: &mut Vec<Diagnostic>) {
+    match node {
+        ASTNode::Program(instructions) | ASTNode::Block(instructions, _) => {
+            for instr in instructions {
+                check_returns(instr, diagnostics);
+            }
+        }
+        ASTNode::FuncDec { name, ret_type, body, span, end_span, .. } => {
+            if *ret_type != Type::Void && !body.contains_error() && !always_returns(body) {
+                diagnostics.push(Diagnostic::error(
+                    diagnostic::MISSING_RETURN,
+                    format!("Not all paths of `{name}` return a value"),
+                    *end_span,
+                ).with_label(*span, &format!("function `{name}` returns {ret_type}")));
+            }
+            check_returns(body, diagnostics);
+        }
+        _ => {}
+    }
+}
+
+/// Warns about statements that follow a `return` (or an endless loop) in the
+/// same block.
+pub fn check_unreachable(node: &ASTNode, diagnostics: &mut Vec<Diagnostic>) {
+    match node {
+        ASTNode::Program(instructions) => {
+            for instr in instructions {
+                check_unreachable(instr, diagnostics);
+            }
+        }
+        ASTNode::Block(instructions, _) => {
+            if let Some(exit) = instructions.iter().position(always_returns) {
+                if let (Some(first), Some(last)) = (instructions.get(exit + 1), instructions.last()) {
+                    diagnostics.push(Diagnostic::warning(
+                        diagnostic::UNREACHABLE_CODE,
+                        "Unreachable code".to_string(),
+                        first.span().to(last.span()),
+                    ).with_label(instructions[exit].span(), "any code following this statement is unreachable"));
+                }
+            }
+            for instr in instructions {
+                check_unreachable(instr, diagnostics);
+            }
+        }
+        ASTNode::FuncDec { body, .. }
+        | ASTNode::WhileStmt { body, .. }
+        | ASTNode::DoWhileStmt { body, .. }
+        | ASTNode::ForStmt { body, .. } => check_unreachable(body, diagnostics),
+        ASTNode::IfStmt { if_branch, else_branch, .. } => {
+            check_unreachable(if_branch, diagnostics);
+            if let Some(else_branch) = else_branch {
+                check_unreachable(else_branch, diagnostics);
+            }
+        }
+        _ => {}
+    }
+}
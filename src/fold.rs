@@ -0,0 +1,192 @@
+use crate::parser::ASTNode;
+use crate::symbols::SymbolTable;
+use crate::types::Type;
+
+/// Compile-time evaluation of integer constants: uses of a `const int`
+/// whose initialiser is a constant expression are replaced with its value,
+/// and operators whose operands are all literals are folded, so that
+/// `const int N = 4;` turns `N * 2` into `8`. Meant to run on programs that
+/// passed every check.
+pub struct ConstantFolder {
+    // `Some(value)` for a foldable constant and `None` for every other name,
+    // so that a local or a parameter hides the constant it shadows.
+    symbols: SymbolTable<Option<i64>>,
+}
+
+impl ConstantFolder {
+    pub fn new() -> Self {
+        ConstantFolder { symbols: SymbolTable::new() }
+    }
+
+    pub fn fold_program(mut self, program: &mut ASTNode) {
+        self.fold(program);
+    }
+
+    fn fold(&mut self, node: &mut ASTNode) {
+        let folded = match node {
+            ASTNode::Program(instructions) => {
+                for instr in instructions {
+                    self.fold(instr);
+                }
+                None
+            }
+            ASTNode::FuncDec { params, body, .. } => {
+                self.symbols.push_scope();
+                for (_, param_name, _) in params.iter() {
+                    self.symbols.declare(param_name, None);
+                }
+                self.fold(body);
+                self.symbols.pop_scope();
+                None
+            }
+            ASTNode::Block(instructions, _) => {
+                self.symbols.push_scope();
+                for instr in instructions {
+                    self.fold(instr);
+                }
+                self.symbols.pop_scope();
+                None
+            }
+            ASTNode::VarDec { var_type, name, initializer, .. } => {
+                if let Some(initializer) = initializer {
+                    self.fold(initializer);
+                }
+                let value = match (var_type, initializer.as_deref()) {
+                    (Type::Const(inner), Some(ASTNode::IntLiteral(value, _))) if **inner == Type::Int => Some(*value),
+                    _ => None,
+                };
+                self.symbols.declare(name, value);
+                None
+            }
+            ASTNode::Identifier(name, span) => match self.symbols.lookup(name) {
+                Some(Some(value)) => Some(ASTNode::IntLiteral(*value, *span)),
+                _ => None,
+            },
+            ASTNode::UnaryOP { operator, operand, span } => {
+                let op = operator.as_operator().map_or("", |op| op.as_str());
+                // `&N` needs the variable itself, not its value.
+                if op != "&" || !matches!(**operand, ASTNode::Identifier(..)) {
+                    self.fold(operand);
+                }
+                match **operand {
+                    ASTNode::IntLiteral(value, _) => evaluate_unary(op, value).map(|value| ASTNode::IntLiteral(value, *span)),
+                    _ => None,
+                }
+            }
+            ASTNode::BinaryOP { operator, left, right, span } => {
+                self.fold(left);
+                self.fold(right);
+                let op = operator.as_operator().map_or("", |op| op.as_str());
+                match (&**left, &**right) {
+                    (ASTNode::IntLiteral(left, _), ASTNode::IntLiteral(right, _)) => {
+                        evaluate_binary(op, *left, *right).map(|value| ASTNode::IntLiteral(value, *span))
+                    }
+                    _ => None,
+                }
+            }
+            // A constant condition picks one of the branches.
+            ASTNode::TernaryOP { condition, then_branch, else_branch, .. } => {
+                self.fold(condition);
+                self.fold(then_branch);
+                self.fold(else_branch);
+                match **condition {
+                    ASTNode::IntLiteral(0, _) => Some(std::mem::replace(&mut **else_branch, ASTNode::Error(Default::default()))),
+                    ASTNode::IntLiteral(..) => Some(std::mem::replace(&mut **then_branch, ASTNode::Error(Default::default()))),
+                    _ => None,
+                }
+            }
+            ASTNode::PostfixOP { operand, .. } => {
+                self.fold(operand);
+                None
+            }
+            ASTNode::Assignment { left_term, right_term, .. } => {
+                self.fold(left_term);
+                self.fold(right_term);
+                None
+            }
+            ASTNode::Index { array, index, .. } => {
+                self.fold(array);
+                self.fold(index);
+                None
+            }
+            ASTNode::ArrayLiteral(elements, _) | ASTNode::Call { args: elements, .. } => {
+                for elem in elements {
+                    self.fold(elem);
+                }
+                None
+            }
+            ASTNode::ReturnStmt(value, _) => {
+                if let Some(value) = value {
+                    self.fold(value);
+                }
+                None
+            }
+            ASTNode::IfStmt { condition, if_branch, else_branch, .. } => {
+                self.fold(condition);
+                self.fold(if_branch);
+                if let Some(else_branch) = else_branch {
+                    self.fold(else_branch);
+                }
+                None
+            }
+            ASTNode::WhileStmt { condition, body, .. } | ASTNode::DoWhileStmt { body, condition, .. } => {
+                self.fold(condition);
+                self.fold(body);
+                None
+            }
+            ASTNode::ForStmt { init, condition, step, body, .. } => {
+                self.symbols.push_scope();
+                for clause in [init, condition, step].into_iter().flatten() {
+                    self.fold(clause);
+                }
+                self.fold(body);
+                self.symbols.pop_scope();
+                None
+            }
+            ASTNode::IntLiteral(..)
+            | ASTNode::FloatLiteral(..)
+            | ASTNode::CharLiteral(..)
+            | ASTNode::StringLiteral(..)
+            | ASTNode::Error(_) => None,
+        };
+        if let Some(folded) = folded {
+            *node = folded;
+        }
+    }
+}
+
+fn evaluate_unary(op: &str, value: i64) -> Option<i64> {
+    match op {
+        "-" => value.checked_neg(),
+        "+" => Some(value),
+        "~" => Some(!value),
+        "!" => Some(i64::from(value == 0)),
+        _ => None,
+    }
+}
+
+// Operations that would overflow or divide by zero are left for the
+// program to perform at run time.
+fn evaluate_binary(op: &str, left: i64, right: i64) -> Option<i64> {
+    match op {
+        "+" => left.checked_add(right),
+        "-" => left.checked_sub(right),
+        "*" => left.checked_mul(right),
+        "/" => left.checked_div(right),
+        "%" => left.checked_rem(right),
+        "<<" => u32::try_from(right).ok().and_then(|right| left.checked_shl(right)),
+        ">>" => u32::try_from(right).ok().and_then(|right| left.checked_shr(right)),
+        "&" => Some(left & right),
+        "|" => Some(left | right),
+        "^" => Some(left ^ right),
+        "==" => Some(i64::from(left == right)),
+        "!=" => Some(i64::from(left != right)),
+        "<" => Some(i64::from(left < right)),
+        ">" => Some(i64::from(left > right)),
+        "<=" => Some(i64::from(left <= right)),
+        ">=" => Some(i64::from(left >= right)),
+        "&&" => Some(i64::from(left != 0 && right != 0)),
+        "||" => Some(i64::from(left != 0 || right != 0)),
+        _ => None,
+    }
+}
@@ -1,5 +1,5 @@
-use std::fs::{self};
 use unwrap_enum::{EnumAs, EnumIs};
+use crate::diagnostic::{self, Diagnostic};
 /*static KEYWORDS: [&str; 34] = [
    "auto", "break", "case", "char", "const", "continue",
    "default", "do", "double",  "else", "enum", "extern",
@@ -21,13 +21,13 @@ static OPERATORS: [&str; 37] = [
     "&&", "||", "!", //Logical operators
     "&", "|", "^", "~", "<<", ">>", //Bitwise operators
     "=", "+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=", "<<=", ">>=", //Assignment operators
-    "?:", ".", "->", "&"  //Other operators
+    "?", ":", ".", "->"  //Other operators
 ];
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq, Debug, Clone, EnumAs, EnumIs)]
 pub enum TokType {
     EOF,
-    ILLEGAL,
     IDENTIFIER(String),
     NUMBER(String),
     OPERATOR(String),
@@ -41,31 +41,177 @@ pub enum TokType {
     COMMA(char),
     KEYWORD(String),
     STRING(String),
+    CHAR(char),
+    FLOAT(String),
 }
 
-fn get_keyword_token(ident: &Vec<char>) -> Result<TokType, String> {
-    let identifier: String = ident.into_iter().collect();
-    for keyword in KEYWORDS.iter() {
-        if keyword.to_string() == identifier {
-            return Ok(TokType::KEYWORD(identifier))
+// How a token is written in the source, for diagnostics: "expected `;`,
+// found `return`".
+impl std::fmt::Display for TokType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokType::EOF => write!(f, "end of file"),
+            TokType::IDENTIFIER(text) | TokType::NUMBER(text) | TokType::OPERATOR(text) | TokType::KEYWORD(text) | TokType::FLOAT(text) => write!(f, "`{text}`"),
+            TokType::LPAREN(ch) | TokType::RPAREN(ch) | TokType::LBRACE(ch) | TokType::RBRACE(ch) | TokType::LSQUARE(ch) | TokType::RSQUARE(ch) | TokType::SEMICOLON(ch) | TokType::COMMA(ch) => write!(f, "`{ch}`"),
+            TokType::STRING(text) => write!(f, "`{text:?}`"),
+            TokType::CHAR(ch) => write!(f, "`{ch:?}`"),
         }
     }
+}
 
-    Err(String::from("Not a keyword"))
+/// Location of a token in a source file: the file id, the byte offsets and
+/// the line/column (1-based) of its first character and of the character
+/// just past its end. Offsets are kept as `u32` (like rustc's `BytePos`) so
+/// that spans stay small enough to be copied around freely.
+#[derive(PartialEq, Eq, Hash, Debug, Clone, Copy, Default)]
+pub struct Span {
+    pub file_id: u32,
+    pub start: u32,
+    pub end: u32,
+    pub start_line: u32,
+    pub start_col: u32,
+    pub end_line: u32,
+    pub end_col: u32,
 }
 
-fn get_operator_token(ident: &Vec<char>) -> Result<TokType, String> {
-    let identifier: String = ident.into_iter().collect();
-    for operator in OPERATORS.iter() {
-        if identifier == operator.to_string() {
-            return Ok(TokType::OPERATOR(identifier));
+impl Span {
+    /// Span running from the start of `self` to the end of `other`.
+    pub fn to(&self, other: Span) -> Span {
+        Span {
+            end: other.end,
+            end_line: other.end_line,
+            end_col: other.end_col,
+            ..*self
         }
     }
-    Err(String::from("Not an operator"))
+
+    /// Whether the two spans share a character or touch each other.
+    pub fn overlaps(&self, other: Span) -> bool {
+        self.file_id == other.file_id && self.start <= other.end && other.start <= self.end
+    }
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.start_line, self.start_col)
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Token {
+    pub kind: TokType,
+    pub span: Span,
+    // Text of the `///` comments right before the token, for tooling.
+    pub doc: Option<String>,
+}
+
+/// Source text that isn't part of any token.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub enum TriviaKind {
+    Whitespace,
+    Newline,
+    LineComment,
+    DocComment,
+    BlockComment,
+    // A character that was reported as invalid and skipped.
+    Skipped,
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Trivia {
+    pub kind: TriviaKind,
+    pub text: String,
+    pub span: Span,
+}
+
+/// A token from `tokenize_lossless`, with its exact text and the trivia
+/// around it.
+#[derive(PartialEq, Debug, Clone)]
+pub struct LosslessToken {
+    pub token: Token,
+    pub text: String,
+    pub leading: Vec<Trivia>,
+    pub trailing: Vec<Trivia>,
+}
+
+fn get_keyword_token(identifier: &str) -> Option<TokType> {
+    KEYWORDS.contains(&identifier).then(|| TokType::KEYWORD(identifier.to_string()))
+}
+
+// Maximal munch: the longest operator the input starts with, so that `a=-1`
+// is `=` then `-` and `x+++y` is `++` then `+`.
+fn longest_operator(input: &str) -> Option<&'static str> {
+    OPERATORS.iter()
+        .filter(|operator| input.starts_with(*operator))
+        .max_by_key(|operator| operator.len())
+        .copied()
+}
+
+// Accepted (case-insensitively) after an integer literal. The language has
+// a single 64-bit int, so they don't change its type.
+const INTEGER_SUFFIXES: [&str; 8] = ["", "u", "l", "ul", "lu", "ll", "ull", "llu"];
+
+/// Value of an integer literal the lexer accepted, or None if it doesn't fit
+/// in an int.
+pub fn int_value(text: &str) -> Option<i64> {
+    let text = text.to_ascii_lowercase();
+    let (radix, digits) = if let Some(digits) = text.strip_prefix("0x") {
+        (16, digits)
+    } else if let Some(digits) = text.strip_prefix("0o") {
+        (8, digits)
+    } else if let Some(digits) = text.strip_prefix("0b") {
+        (2, digits)
+    } else if text.len() > 1 && text.starts_with('0') && text[1..].starts_with(|ch: char| ch.is_ascii_digit() || ch == '_') {
+        // Same rule as `read_number`: a leading 0 followed by more digits or
+        // separators, as in `017` or `0_17`, makes the literal octal.
+        (8, &text[1..])
+    } else {
+        (10, text.as_str())
+    };
+    let digits: String = digits.chars().filter(|ch| *ch != '_').take_while(|ch| ch.is_digit(radix)).collect();
+    i64::from_str_radix(&digits, radix).ok()
+}
+
+// Unicode characters that are easily mistaken for ASCII ones, usually after
+// copying code from a document: the character, its name and the ASCII
+// character it looks like.
+const CONFUSABLES: [(char, &str, char); 16] = [
+    ('\u{201C}', "Left Double Quotation Mark", '"'),
+    ('\u{201D}', "Right Double Quotation Mark", '"'),
+    ('\u{2018}', "Left Single Quotation Mark", '\''),
+    ('\u{2019}', "Right Single Quotation Mark", '\''),
+    ('\u{2212}', "Minus Sign", '-'),
+    ('\u{2013}', "En Dash", '-'),
+    ('\u{2014}', "Em Dash", '-'),
+    ('\u{00D7}', "Multiplication Sign", '*'),
+    ('\u{2215}', "Division Slash", '/'),
+    ('\u{037E}', "Greek Question Mark", ';'),
+    ('\u{FF1B}', "Fullwidth Semicolon", ';'),
+    ('\u{FF0C}', "Fullwidth Comma", ','),
+    ('\u{FF08}', "Fullwidth Left Parenthesis", '('),
+    ('\u{FF09}', "Fullwidth Right Parenthesis", ')'),
+    ('\u{FF1D}', "Fullwidth Equals Sign", '='),
+    ('\u{00A0}', "No-Break Space", ' '),
+];
+
+/// Lexer settings chosen on the command line.
+#[derive(Debug, Default)]
+pub struct Options {
+    /// Accept identifiers made of Unicode letters (XID_Start followed by
+    /// XID_Continue), not just `[A-Za-z_][A-Za-z0-9_]*`.
+    pub unicode_identifiers: bool,
+}
+
+fn is_identifier_start(ch: char) -> bool {
+    is_letter(ch) || (!ch.is_ascii() && unicode_ident::is_xid_start(ch))
+}
+
+fn is_identifier_continue(ch: char) -> bool {
+    is_letter(ch) || is_digit(ch) || (!ch.is_ascii() && unicode_ident::is_xid_continue(ch))
 }
 
 fn is_letter(ch: char) -> bool {
-    'a' <= ch && ch <= 'z' || 'A' <= ch && ch <= 'Z' || ch == '_'
+    ch.is_ascii_lowercase() || ch.is_ascii_uppercase() || ch == '_'
 }
 
 fn is_digit(ch: char) -> bool {
@@ -76,181 +222,548 @@ fn is_whitespace(ch: char) -> bool {
     ch == ' ' || ch == '\t' || ch == '\n' || ch == '\r'
 }
 
-pub struct Lexer {
-    input: Vec<char>,
+/// Turns source text into tokens on demand. The lexer works directly on
+/// the borrowed text, by byte offset, and yields one token at a time (see
+/// the `Iterator` impl), so the parser never needs the whole token list.
+pub struct Lexer<'a> {
+    input: &'a str,
+    // Byte offsets of the current character and of the one after it.
     position: usize,
     read_position: usize,
     ch: char,
-    comment: bool,
+    diagnostics: Vec<Diagnostic>,
+    unicode_identifiers: bool,
+    file_id: u32,
+    line: u32,
+    column: u32,
+    // Offset, line and column where the current token starts.
+    token_start: (u32, u32, u32),
+    // Trivia skipped since the last token, only kept by `tokenize_lossless`.
+    trivia: Option<Vec<Trivia>>,
+    // Set once the EOF token has been yielded.
+    finished: bool,
 }
 
-impl Lexer {
-    pub fn new(input: Vec<char>) -> Self {
-        Self {
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a str, file_id: u32, options: &Options) -> Self {
+        let mut lexer = Self {
             input,
             position: 0,
             read_position: 0,
             ch: '\0',
-            comment: false,
-        }
+            diagnostics: Vec::new(),
+            unicode_identifiers: options.unicode_identifiers,
+            file_id,
+            line: 1,
+            column: 1,
+            token_start: (0, 1, 1),
+            trivia: None,
+            finished: false,
+        };
+        lexer.read_char();
+        lexer
+    }
+
+    /// Errors found so far, which are removed from the lexer.
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
     }
 
     fn read_char(&mut self) {
-        if self.read_position >= self.input.len() {
-            self.ch = '\0';
-        } else {
-            self.ch = self.input[self.read_position];
+        if self.read_position > 0 && self.position < self.input.len() {
+            if self.ch == '\n' {
+                self.line += 1;
+                self.column = 1;
+            } else {
+                self.column += 1;
+            }
         }
-        self.position = self.read_position;
-        self.read_position = self.read_position + 1;
+        self.position = self.read_position.min(self.input.len());
+        self.ch = self.input[self.position..].chars().next().unwrap_or('\0');
+        self.read_position = self.position + self.ch.len_utf8();
     }
 
-    fn skip_whitespace(&mut self) {
-        let ch = self.ch;
-        if is_whitespace(ch) {
+    fn peek_char(&self) -> char {
+        self.input.get(self.read_position..).and_then(|rest| rest.chars().next()).unwrap_or('\0')
+    }
+
+    fn mark(&self) -> (u32, u32, u32) {
+        (self.position as u32, self.line, self.column)
+    }
+
+    // Span from `start` (as returned by `mark`) to the current character.
+    fn span_from(&self, start: (u32, u32, u32)) -> Span {
+        let (start, start_line, start_col) = start;
+        Span {
+            file_id: self.file_id,
+            start,
+            end: self.position as u32,
+            start_line,
+            start_col,
+            end_line: self.line,
+            end_col: self.column,
+        }
+    }
+
+    // Reads the escape sequence starting at the current `\`, returning the
+    // character it stands for, or None (after reporting it) if it's unknown.
+    fn read_escape(&mut self) -> Option<char> {
+        let start = self.mark();
+        let start_position = self.position;
+        self.read_char();
+        let escaped = match self.ch {
+            'n' => Some('\n'),
+            't' => Some('\t'),
+            'r' => Some('\r'),
+            '0' => Some('\0'),
+            '\\' => Some('\\'),
+            '\'' => Some('\''),
+            '"' => Some('"'),
+            'x' => {
+                self.read_char();
+                let digits_position = self.position;
+                while self.ch.is_ascii_hexdigit() && self.position - digits_position < 2 {
+                    self.read_char();
+                }
+                let digits = &self.input[digits_position..self.position];
+                // Strings hold characters rather than bytes, so only the
+                // escapes that are a single byte in UTF-8 are allowed.
+                match u8::from_str_radix(digits, 16) {
+                    Ok(value) if value.is_ascii() => return Some(char::from(value)),
+                    Ok(_) => {
+                        self.diagnostics.push(Diagnostic::error(
+                            diagnostic::INVALID_LITERAL,
+                            format!("`\\x{digits}` is out of range for a character"),
+                            self.span_from(start),
+                        ).with_note("`\\x` escapes go up to `\\x7f`"));
+                        return None;
+                    }
+                    Err(_) => {}
+                }
+                self.diagnostics.push(Diagnostic::error(
+                    diagnostic::INVALID_LITERAL,
+                    "Missing hexadecimal digits after `\\x`".to_string(),
+                    self.span_from(start),
+                ));
+                return None;
+            }
+            _ => None,
+        };
+        if escaped.is_none() && self.ch != '\n' && !self.at_end() {
             self.read_char();
+            self.diagnostics.push(Diagnostic::error(
+                diagnostic::INVALID_LITERAL,
+                format!("Unknown escape sequence `{}`", &self.input[start_position..self.position]),
+                self.span_from(start),
+            ));
+            return None;
         }
+        if escaped.is_some() {
+            self.read_char();
+        }
+        escaped
     }
 
-    fn handle_comments(&mut self, type_comment: &str) -> Result<TokType, String>{
-        match type_comment {
-            "//" => {
-                self.ch = '\0';
-                return Ok(TokType::EOF);
-            },
-            "/*" => {
-                self.comment = true;
-                return Ok(TokType::EOF);
-            },
-            _ => {},
+    // Contents of a string literal with its escapes decoded. A string can't
+    // span several lines.
+    fn read_string_literal(&mut self) -> TokType {
+        self.read_char();
+        let mut contents = String::new();
+        while self.ch != '"' {
+            match self.ch {
+                _ if self.ch == '\n' || self.at_end() => {
+                    self.diagnostics.push(Diagnostic::error(
+                        diagnostic::INVALID_LITERAL,
+                        "Unterminated string literal".to_string(),
+                        self.span_from(self.token_start),
+                    ).with_note("strings must be closed by a `\"` on the same line"));
+                    return TokType::STRING(contents);
+                }
+                '\\' => {
+                    if let Some(ch) = self.read_escape() {
+                        contents.push(ch);
+                    }
+                }
+                ch => {
+                    contents.push(ch);
+                    self.read_char();
+                }
+            }
         }
-        return Ok(TokType::EOF);
+        self.read_char();
+        TokType::STRING(contents)
     }
 
-    pub fn next_token(&mut self) -> TokType {
-        let read_identifier = |l: &mut Lexer| -> Vec<char> {
-            let position = l.position;
-            while l.position < l.input.len() && is_letter(l.ch) {
-                l.read_char();
+    // 'x' or '\n': exactly one (possibly escaped) character between quotes.
+    fn read_char_literal(&mut self) -> TokType {
+        self.read_char();
+        let mut len = 1;
+        let value = match self.ch {
+            '\\' => self.read_escape(),
+            _ if matches!(self.ch, '\'' | '\n') || self.at_end() => {
+                len = 0;
+                None
+            }
+            ch => {
+                self.read_char();
+                Some(ch)
             }
-            l.input[position..l.position].to_vec()
         };
+        while self.ch != '\'' && self.ch != '\n' && !self.at_end() {
+            self.read_char();
+            len += 1;
+        }
+        if self.ch != '\'' {
+            self.diagnostics.push(Diagnostic::error(
+                diagnostic::INVALID_LITERAL,
+                "Unterminated character literal".to_string(),
+                self.span_from(self.token_start),
+            ));
+            return TokType::CHAR('\0');
+        }
+        self.read_char();
+        if len != 1 {
+            let message = if len == 0 { "Empty character literal" } else { "A character literal must contain exactly one character" };
+            self.diagnostics.push(Diagnostic::error(diagnostic::INVALID_LITERAL, message.to_string(), self.span_from(self.token_start)));
+        }
+        TokType::CHAR(value.unwrap_or('\0'))
+    }
 
-        let read_number = |l: &mut Lexer| -> Vec<char> {
-            let position = l.position;
-            while l.position < l.input.len() && is_digit(l.ch) {
-                l.read_char();
-            }
-            l.input[position..l.position].to_vec()
+    fn skip_digits(&mut self, radix: u32) {
+        while self.ch.is_digit(radix) || self.ch == '_' {
+            self.read_char();
+        }
+    }
+
+    // Integers are decimal, `0x` hexadecimal, `0o` or leading-zero octal, or
+    // `0b` binary, may use `_` between digits and may end with a `u`/`l`
+    // suffix. A fraction or an exponent makes a decimal number a float:
+    // `3.14`, `1e9`, `2.5e-3`. Malformed and out-of-range literals are
+    // reported here, so the parser can trust the text of the token.
+    fn read_number(&mut self) -> TokType {
+        let start_position = self.position;
+        let mut radix = match (self.ch, self.peek_char()) {
+            ('0', 'x' | 'X') => 16,
+            ('0', 'o' | 'O') => 8,
+            ('0', 'b' | 'B') => 2,
+            _ => 10,
         };
-       //TODO aggiustare questa funzione, se scrivo =; giustamente da errore
-       let read_operator = |l: &mut Lexer| -> Vec<char> {
-            let position = l.position;
-            while l.position < l.input.len() && !is_letter(l.ch) && !is_digit(l.ch) && !is_whitespace(l.ch) && l.ch != '"' {
-                l.read_char();
+        if radix != 10 {
+            self.read_char();
+            self.read_char();
+        }
+        let digits_position = self.position;
+        let mut is_float = false;
+        if radix == 10 {
+            self.skip_digits(10);
+            if self.ch == '.' && is_digit(self.peek_char()) {
+                is_float = true;
+                self.read_char();
+                self.skip_digits(10);
+            }
+            let signed = matches!(self.peek_char(), '+' | '-') && self.input[self.read_position..].chars().nth(1).is_some_and(is_digit);
+            if (self.ch == 'e' || self.ch == 'E') && (is_digit(self.peek_char()) || signed) {
+                is_float = true;
+                self.read_char();
+                self.read_char();
+                self.skip_digits(10);
+            }
+            if !is_float && self.ch != '.' && self.input[start_position..].starts_with('0') && self.position - start_position > 1 {
+                radix = 8;
             }
-            l.input[position..l.position].to_vec()
+        } else {
+            self.skip_digits(radix);
+        }
+        let digits_end = self.position;
+        while is_letter(self.ch) || is_digit(self.ch) {
+            self.read_char();
+        }
+
+        let text = self.input[start_position..self.position].to_string();
+        let token = if is_float { TokType::FLOAT(text.clone()) } else { TokType::NUMBER(text.clone()) };
+        let digits: Vec<char> = self.input[digits_position..digits_end].chars().collect();
+        let suffix = &self.input[digits_end..self.position];
+        let base = match radix {
+            16 => "hexadecimal",
+            8 => "octal",
+            2 => "binary",
+            _ => "decimal",
+        };
+        let misplaced_separator = digits.iter().enumerate().any(|(index, ch)| {
+            *ch == '_' && !(index > 0 && digits[index - 1].is_digit(radix) && digits.get(index + 1).is_some_and(|next| next.is_digit(radix)))
+        });
+        let first_suffix = suffix.chars().next();
+        let error = if digits.is_empty() {
+            Some((format!("Missing digits after `{text}`"), None))
+        } else if let Some(digit) = digits.iter().copied().chain(first_suffix).find(|ch| is_digit(*ch) && !ch.is_digit(radix)) {
+            Some((format!("Invalid digit `{digit}` in {base} literal `{text}`"), None))
+        } else if misplaced_separator {
+            Some(("Digit separators `_` must be placed between two digits".to_string(), None))
+        } else if is_float && !suffix.is_empty() {
+            Some((format!("Invalid suffix `{suffix}` for a float literal"), None))
+        } else if !is_float && !INTEGER_SUFFIXES.contains(&suffix.to_ascii_lowercase().as_str()) {
+            Some((format!("Invalid suffix `{suffix}` for an integer literal"), Some("integer literals can end with u, l, ul, ll or ull")))
+        } else {
+            None
         };
+        if let Some((message, note)) = error {
+            let mut diagnostic = Diagnostic::error(diagnostic::INVALID_LITERAL, message, self.span_from(self.token_start));
+            if let Some(note) = note {
+                diagnostic = diagnostic.with_note(note);
+            }
+            self.diagnostics.push(diagnostic);
+        } else if !is_float && int_value(&text).is_none() {
+            self.diagnostics.push(Diagnostic::error(
+                diagnostic::INVALID_LITERAL,
+                format!("Integer literal `{text}` is too large for an int"),
+                self.span_from(self.token_start),
+            ).with_note(&format!("the largest int is {}", i64::MAX)));
+        }
+        token
+    }
+
+    fn at_end(&self) -> bool {
+        self.position >= self.input.len()
+    }
 
-        let read_string = |l: &mut Lexer| -> Vec<char> {
-            let position = l.position;
-            let mut count = 0;
-            let mut escape: bool = false;
-            while l.position < l.input.len() && count < 2 {
-                if l.ch == '"' && !escape{
-                    count = count + 1;
+    fn push_trivia(&mut self, kind: TriviaKind, start_position: usize, start: (u32, u32, u32)) {
+        let span = self.span_from(start);
+        if let Some(trivia) = &mut self.trivia {
+            let text = self.input[start_position..self.position].to_string();
+            trivia.push(Trivia { kind, text, span });
+        }
+    }
+
+    // Skips whitespace, `// line` and `/* block */` comments, returning the
+    // text of the `///` doc comments found on the way, if any. `////` is an
+    // ordinary comment, as in Rust.
+    fn skip_trivia(&mut self) -> Option<String> {
+        let mut doc: Option<String> = None;
+        loop {
+            let start_position = self.position;
+            let start = self.mark();
+            if self.ch == '\n' {
+                self.read_char();
+                self.push_trivia(TriviaKind::Newline, start_position, start);
+            } else if is_whitespace(self.ch) {
+                while is_whitespace(self.ch) && self.ch != '\n' {
+                    self.read_char();
                 }
-                if l.ch == '\\' {
-                    escape = true;
+                self.push_trivia(TriviaKind::Whitespace, start_position, start);
+            } else if self.ch == '/' && self.peek_char() == '/' {
+                while self.ch != '\n' && !self.at_end() {
+                    self.read_char();
+                }
+                let text = &self.input[start_position..self.position];
+                if let Some(line) = text.strip_prefix("///").filter(|line| !line.starts_with('/')) {
+                    let doc = doc.get_or_insert_with(String::new);
+                    if !doc.is_empty() {
+                        doc.push('\n');
+                    }
+                    doc.push_str(line.strip_prefix(' ').unwrap_or(line).trim_end());
+                    self.push_trivia(TriviaKind::DocComment, start_position, start);
                 } else {
-                    escape = false;
+                    self.push_trivia(TriviaKind::LineComment, start_position, start);
                 }
-                l.read_char();
+            } else if self.ch == '/' && self.peek_char() == '*' {
+                self.read_char();
+                self.read_char();
+                let opener = self.span_from(start);
+                while !self.at_end() && !self.input[self.position..].starts_with("*/") {
+                    self.read_char();
+                }
+                if self.at_end() {
+                    self.diagnostics.push(Diagnostic::error(diagnostic::UNTERMINATED_COMMENT, "Unterminated block comment".to_string(), opener)
+                        .with_note("block comments are closed by `*/`"));
+                    self.push_trivia(TriviaKind::BlockComment, start_position, start);
+                    return doc;
+                }
+                self.read_char();
+                self.read_char();
+                self.push_trivia(TriviaKind::BlockComment, start_position, start);
+            } else {
+                return doc;
             }
-            l.input[position..l.position].to_vec()
-        };
+        }
+    }
 
-        let token: TokType;
-        while is_whitespace(self.ch) {
-            self.skip_whitespace();
+    fn next_token(&mut self) -> Token {
+        loop {
+            let doc = self.skip_trivia();
+            self.token_start = self.mark();
+            let start_position = self.position;
+            if let Some(kind) = self.next_kind() {
+                return Token { kind, span: self.span_from(self.token_start), doc };
+            }
+            self.push_trivia(TriviaKind::Skipped, start_position, self.token_start);
         }
+    }
 
-        if self.comment == true {
-            return TokType::EOF;
+    // Reports the current character, which can't start any token, and skips
+    // it. Characters that look like ASCII punctuation get a dedicated
+    // message, since they usually come from pasted text.
+    fn invalid_character(&mut self) -> Option<TokType> {
+        let ch = self.ch;
+        self.read_char();
+        let span = self.span_from(self.token_start);
+        let diagnostic = match CONFUSABLES.iter().find(|(confusable, ..)| *confusable == ch) {
+            Some((_, name, ascii)) => Diagnostic::error(
+                diagnostic::INVALID_CHARACTER,
+                format!("Unicode character `{ch}` ({name}) looks like `{ascii}`, but it is not"),
+                span,
+            ).with_note(&format!("replace it with `{ascii}`")),
+            None => Diagnostic::error(
+                diagnostic::INVALID_CHARACTER,
+                format!("Invalid character `{}` in the source", ch.escape_default()),
+                span,
+            ),
+        };
+        self.diagnostics.push(diagnostic);
+        None
+    }
+
+    fn read_identifier(&mut self) -> TokType {
+        let start_position = self.position;
+        while is_identifier_continue(self.ch) {
+            self.read_char();
         }
-        match self.ch {
-            '\0' => token = TokType::EOF,
-            '(' => token = TokType::LPAREN(self.ch),
-            ')' => token = TokType::RPAREN(self.ch),
-            '{' => token = TokType::LBRACE(self.ch),
-            '}' => token = TokType::RBRACE(self.ch),
-            '[' => token = TokType::LSQUARE(self.ch),
-            ']' => token = TokType::RSQUARE(self.ch),
-            ';' => token = TokType::SEMICOLON(self.ch),
-            ',' => token = TokType::COMMA(self.ch),
+        let ident = &self.input[start_position..self.position];
+        if !self.unicode_identifiers && !ident.is_ascii() {
+            self.diagnostics.push(Diagnostic::error(
+                diagnostic::INVALID_CHARACTER,
+                format!("Identifier `{ident}` contains non-ASCII characters"),
+                self.span_from(self.token_start),
+            ).with_note("identifiers are made of ASCII letters, digits and `_`; pass -funicode-identifiers to allow Unicode letters"));
+        }
+        get_keyword_token(ident).unwrap_or_else(|| TokType::IDENTIFIER(ident.to_string()))
+    }
+
+    // The kind of the token starting at the current character, or None if
+    // the character was invalid and has been skipped.
+    fn next_kind(&mut self) -> Option<TokType> {
+        // A NUL byte in the middle of the file is an invalid character, not
+        // the end of the input.
+        let token = match self.ch {
+            _ if self.at_end() => TokType::EOF,
+            '(' => TokType::LPAREN(self.ch),
+            ')' => TokType::RPAREN(self.ch),
+            '{' => TokType::LBRACE(self.ch),
+            '}' => TokType::RBRACE(self.ch),
+            '[' => TokType::LSQUARE(self.ch),
+            ']' => TokType::RSQUARE(self.ch),
+            ';' => TokType::SEMICOLON(self.ch),
+            ',' => TokType::COMMA(self.ch),
+            '\'' => return Some(self.read_char_literal()),
+            '"' => return Some(self.read_string_literal()),
+            ch if is_identifier_start(ch) => return Some(self.read_identifier()),
+            ch if is_digit(ch) => return Some(self.read_number()),
             _ => {
-                if !is_letter(self.ch) && !is_digit(self.ch) && !is_whitespace(self.ch) && self.ch != '"'{
-                    let operator: Vec<char> = read_operator(self);
-                    match get_operator_token(&operator) {
-                        Ok(operator_token) => {
-                            return operator_token;
-                        }
-                        Err(_err) => {
-                            let op_string: String = operator.iter().collect();
-                            if op_string == "//" || op_string == "/*" {
-                                match self.handle_comments(op_string.as_str()) {
-                                    Ok(comment_token) => {
-                                        return comment_token;
-                                    }
-                                    Err(_err) => { }
-                                }
-                            }
-                            if op_string == "*/" {
-                                self.comment = false;
-                            }
-                        }
-                    }
+                let Some(operator) = longest_operator(&self.input[self.position..]) else {
+                    return self.invalid_character();
+                };
+                for _ in operator.chars() {
+                    self.read_char();
                 }
+                return Some(TokType::OPERATOR(operator.to_string()));
+            }
+        };
+        self.read_char();
+        Some(token)
+    }
+}
 
-                if self.ch == '"' {
-                    let string: Vec<char> = read_string(self);
-                    return TokType::STRING(string.into_iter().collect());
-                }
+/// Yields the tokens of the input, ending with a single EOF token.
+impl Iterator for Lexer<'_> {
+    type Item = Token;
 
-               if is_letter(self.ch) {
-                    let ident: Vec<char> = read_identifier(self);
-                    match get_keyword_token(&ident) {
-                        Ok(keyword_token) => {
-                            return keyword_token;
-                        },
-                        Err(_err) => {
-                            return TokType::IDENTIFIER(ident.into_iter().collect());
-                        }
-                    }
-                } else if is_digit(self.ch) {
-                    let ident: String = read_number(self).into_iter().collect();
-                    return TokType::NUMBER(ident);
-                }
-                return TokType::ILLEGAL
-            }
+    fn next(&mut self) -> Option<Token> {
+        if self.finished {
+            return None;
         }
-        self.read_char();
-        token
+        let token = self.next_token();
+        self.finished = token.kind == TokType::EOF;
+        Some(token)
     }
 }
 
-pub fn tokenize_file(file_path: String) -> Vec<TokType> {
-    let contents = fs::read_to_string(file_path).expect("Should have been able to open the file");
-    let mut lexer = Lexer::new(contents.chars().collect());
-    let mut tokens = Vec::new();
-    lexer.read_char();
-    loop {
-        let token = lexer.next_token();
-        if token == TokType::EOF {
-            break;
-        } else {
-            tokens.push(token);
+/// Lexing for tools that rewrite source code (a formatter, refactorings, an
+/// LSP): every byte of `contents` ends up either in a token or in the trivia
+/// attached to one, so `source_text` gives the file back unchanged. A token
+/// owns the trivia that follows it up to the end of its line, and everything
+/// else belongs to the next token; the end of the file is the leading trivia
+/// of EOF. Errors come with the tokens rather than instead of them, since
+/// tools have to cope with broken code.
+pub fn tokenize_lossless(contents: &str, file_id: u32, options: &Options) -> (Vec<LosslessToken>, Vec<Diagnostic>) {
+    let mut lexer = Lexer::new(contents, file_id, options);
+    lexer.trivia = Some(Vec::new());
+    let mut tokens: Vec<LosslessToken> = Vec::new();
+    while let Some(token) = lexer.next() {
+        let mut leading = lexer.trivia.replace(Vec::new()).unwrap_or_default();
+        if let Some(previous) = tokens.last_mut() {
+            let end_of_line = leading.iter().position(|trivia| trivia.kind == TriviaKind::Newline).unwrap_or(leading.len());
+            previous.trailing = leading.drain(..end_of_line).collect();
         }
+        let text = contents[token.span.start as usize..token.span.end as usize].to_string();
+        tokens.push(LosslessToken { token, text, leading, trailing: Vec::new() });
+    }
+    (tokens, lexer.diagnostics)
+}
+
+/// The source text a lossless token stream was lexed from.
+pub fn source_text(tokens: &[LosslessToken]) -> String {
+    let mut text = String::new();
+    for token in tokens {
+        for trivia in &token.leading {
+            text.push_str(&trivia.text);
+        }
+        text.push_str(&token.text);
+        for trivia in &token.trailing {
+            text.push_str(&trivia.text);
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Lexes `contents` losslessly and checks that it comes back unchanged,
+    // returning the diagnostics reported on the way.
+    fn round_trip(contents: &str) -> Vec<Diagnostic> {
+        let (tokens, diagnostics) = tokenize_lossless(contents, 0, &Options::default());
+        assert_eq!(source_text(&tokens), contents);
+        assert_eq!(tokens.last().map(|token| &token.token.kind), Some(&TokType::EOF));
+        diagnostics
+    }
+
+    #[test]
+    fn round_trips_comments() {
+        let diagnostics = round_trip("/// Doc\nfn f() -> int { // line\n  /* block\n  */ return 0; }\n");
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn round_trips_crlf() {
+        let diagnostics = round_trip("fn f() -> int {\r\n  return 0;\r\n}\r\n");
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn round_trips_unterminated_string() {
+        let diagnostics = round_trip("string s = \"abc\nint x = 1;");
+        assert_eq!(diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn round_trips_unterminated_comment() {
+        let diagnostics = round_trip("int x = 1; /* never closed\n");
+        assert_eq!(diagnostics.len(), 1);
+    }
+
+    #[test]
+    fn round_trips_invalid_characters() {
+        let diagnostics = round_trip("int x = 1 @ 2 \u{2212} 3;\0 garbage (((");
+        assert_eq!(diagnostics.len(), 3);
     }
-    tokens
 }
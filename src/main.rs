@@ -1,11 +1,78 @@
-use std::fs::File;
+use std::io::IsTerminal;
 mod preprocessor;
 mod parser;
 mod lexer;
+mod diagnostic;
+mod renderer;
+mod source;
+mod semantic;
+mod flow;
+mod symbols;
+mod typeck;
+mod types;
+mod fold;
+
+fn report(renderer: &renderer::Renderer, diagnostics: &[diagnostic::Diagnostic]) {
+    for diagnostic in diagnostics {
+        eprintln!("{}", renderer.render(diagnostic));
+    }
+}
+
 fn main() {
-    //let mut preprocessor = preprocessor::Preprocessor::new("src/main.c");
-    //preprocessor.process_file("src/main.c");
+    let mut file_path = String::from("src/main.c");
+    let mut dump_tokens = false;
+    let mut lexer_options = lexer::Options::default();
+    let mut options = semantic::Options::default();
+    for arg in std::env::args().skip(1) {
+        match arg.as_str() {
+            "-Wshadow" => options.warn_shadowing = true,
+            "--dump-tokens" => dump_tokens = true,
+            "-funicode-identifiers" => lexer_options.unicode_identifiers = true,
+            _ => file_path = arg,
+        }
+    }
+
+    let mut sources = source::SourceMap::new();
+    let file_id = sources.load_file(&file_path).expect("Should have been able to open the file");
+
+    // The preprocessed text has the same lines as the file, so it is added
+    // under the same name and the later stages point into it.
+    let mut preprocessor = preprocessor::Preprocessor::new(file_id);
+    if let Some(directory) = std::path::Path::new(&file_path).parent() {
+        preprocessor.add_include_paths(directory);
+    }
+    let preprocessed = preprocessor.process(&sources.get(file_id).contents);
+    let preprocessed_id = sources.add_file(&file_path, preprocessed);
+
+    let renderer = if std::io::stderr().is_terminal() && std::env::var_os("NO_COLOR").is_none() {
+        renderer::Renderer::coloured(&sources)
+    } else {
+        renderer::Renderer::plain(&sources)
+    };
+
+    // Prints every token with the whitespace and comments around it, as a
+    // formatter or an editor would see them.
+    if dump_tokens {
+        let contents = &sources.get(file_id).contents;
+        let (tokens, diagnostics) = lexer::tokenize_lossless(contents, file_id, &lexer_options);
+        debug_assert_eq!(&lexer::source_text(&tokens), contents);
+        report(&renderer, &diagnostics);
+        println!("{:#?}", tokens);
+        return;
+    }
 
-    let tokens_list = lexer::tokenize_file("src/main.c".to_string());
-    parser::parse_program(tokens_list);
+    let mut diagnostics = preprocessor.take_diagnostics();
+    let lexer = lexer::Lexer::new(&sources.get(preprocessed_id).contents, preprocessed_id, &lexer_options);
+    let (mut program, parse_diagnostics) = parser::parse_program(lexer);
+    diagnostics.extend(parse_diagnostics);
+    // The types are what code generation needs to scale pointer arithmetic
+    // and size arrays, once there is a backend to hand them to.
+    let (_types, semantic_diagnostics) = semantic::check_program(&program, &options);
+    diagnostics.extend(semantic_diagnostics);
+    report(&renderer, &diagnostics);
+    if diagnostics.iter().any(|diagnostic| diagnostic.is_error()) {
+        std::process::exit(1);
+    }
+    fold::ConstantFolder::new().fold_program(&mut program);
+    println!("{:#?}", program);
 }
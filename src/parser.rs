@@ -1,356 +1,884 @@
-use regex::Replacer;
-use regex::Regex;
 use crate::lexer;
+use crate::diagnostic::{self, Diagnostic};
+use crate::types::Type;
+#[allow(dead_code)]
 #[derive(Debug)]
-enum ASTNode {
+pub enum ASTNode {
     Program(Vec<ASTNode>),
     FuncDec {
+        // `///` comments written before `fn`.
+        doc: Option<String>,
         name: String,
-        params: Vec<(String, String)>,
-        ret_type: String,
+        // Type, name and span of the name of each parameter.
+        params: Vec<(Type, String, lexer::Span)>,
+        ret_type: Type,
         body: Box<ASTNode>,
+        // `span` is the function name, `end_span` its closing brace.
+        span: lexer::Span,
+        end_span: lexer::Span,
     },
-    Block(Vec<ASTNode>),
+    Block(Vec<ASTNode>, lexer::Span),
     VarDec {
-        var_type: String,
+        var_type: Type,
         name: String,
         initializer: Option<Box<ASTNode>>,
+        span: lexer::Span,
     },
     UnaryOP {
         operator: lexer::TokType,
         operand: Box<ASTNode>,
+        span: lexer::Span,
+    },
+    PostfixOP {
+        operator: lexer::TokType,
+        operand: Box<ASTNode>,
+        span: lexer::Span,
     },
     BinaryOP {
         operator: lexer::TokType,
         left: Box<ASTNode>,
         right: Box<ASTNode>,
+        span: lexer::Span,
+    },
+    // `condition ? then_branch : else_branch`
+    TernaryOP {
+        condition: Box<ASTNode>,
+        then_branch: Box<ASTNode>,
+        else_branch: Box<ASTNode>,
+        span: lexer::Span,
+    },
+    // `array[index]`
+    Index {
+        array: Box<ASTNode>,
+        index: Box<ASTNode>,
+        span: lexer::Span,
     },
-    Identifier(String),
-    IntLiteral(i64),
-    StringLiteral(String),
+    // Brace initialiser of an array, `{1, 2, 3}`, possibly nested.
+    ArrayLiteral(Vec<ASTNode>, lexer::Span),
+    Identifier(String, lexer::Span),
+    IntLiteral(i64, lexer::Span),
+    FloatLiteral(f64, lexer::Span),
+    CharLiteral(char, lexer::Span),
+    StringLiteral(String, lexer::Span),
+    Call {
+        callee: String,
+        args: Vec<ASTNode>,
+        span: lexer::Span,
+    },
+    // `operator` is `=` or one of the compound assignment operators (`+=`, ...)
     Assignment {
+        operator: lexer::TokType,
         left_term: Box<ASTNode>,
         right_term: Box<ASTNode>,
+        span: lexer::Span,
     },
-    ReturnStmt(Box<ASTNode>),
+    ReturnStmt(Option<Box<ASTNode>>, lexer::Span),
+    // `else_branch` is either a Block or, for `else if`, another IfStmt.
     IfStmt {
         condition: Box<ASTNode>,
-        if_branch: Box<Vec<ASTNode>>,
-        else_branch: Option<Box<Vec<ASTNode>>>,
+        if_branch: Box<ASTNode>,
+        else_branch: Option<Box<ASTNode>>,
+        span: lexer::Span,
     },
     WhileStmt {
         condition: Box<ASTNode>,
         body: Box<ASTNode>,
+        span: lexer::Span,
     },
     DoWhileStmt {
         body: Box<ASTNode>,
         condition: Box<ASTNode>,
+        span: lexer::Span,
+    },
+    ForStmt {
+        init: Option<Box<ASTNode>>,
+        condition: Option<Box<ASTNode>>,
+        step: Option<Box<ASTNode>>,
+        body: Box<ASTNode>,
+        span: lexer::Span,
+    },
+    // Placeholder for an instruction that failed to parse, so that later
+    // passes can still run over the rest of the tree.
+    Error(lexer::Span),
+}
+
+impl ASTNode {
+    /// Whether the node names a memory location that can be assigned to or
+    /// have its address taken: a variable, `a[i]` or `*p`.
+    pub fn is_lvalue(&self) -> bool {
+        match self {
+            ASTNode::Identifier(..) | ASTNode::Index { .. } => true,
+            ASTNode::UnaryOP { operator: lexer::TokType::OPERATOR(op), .. } => op == "*",
+            _ => false,
+        }
+    }
+
+    /// Whether a statement that failed to parse was left in this node. Only
+    /// statements are replaced by `Error`, so expressions aren't searched.
+    pub fn contains_error(&self) -> bool {
+        match self {
+            ASTNode::Error(_) => true,
+            ASTNode::Program(instructions) | ASTNode::Block(instructions, _) => instructions.iter().any(ASTNode::contains_error),
+            ASTNode::FuncDec { body, .. }
+            | ASTNode::WhileStmt { body, .. }
+            | ASTNode::DoWhileStmt { body, .. }
+            | ASTNode::ForStmt { body, .. } => body.contains_error(),
+            ASTNode::IfStmt { if_branch, else_branch, .. } => {
+                if_branch.contains_error() || else_branch.as_ref().is_some_and(|else_branch| else_branch.contains_error())
+            }
+            _ => false,
+        }
     }
+
+    pub fn span(&self) -> lexer::Span {
+        match self {
+            ASTNode::UnaryOP { span, .. }
+            | ASTNode::PostfixOP { span, .. }
+            | ASTNode::BinaryOP { span, .. }
+            | ASTNode::TernaryOP { span, .. }
+            | ASTNode::Assignment { span, .. }
+            | ASTNode::Call { span, .. }
+            | ASTNode::Index { span, .. }
+            | ASTNode::FuncDec { span, .. }
+            | ASTNode::VarDec { span, .. }
+            | ASTNode::IfStmt { span, .. }
+            | ASTNode::WhileStmt { span, .. }
+            | ASTNode::DoWhileStmt { span, .. }
+            | ASTNode::ForStmt { span, .. }
+            | ASTNode::Block(_, span)
+            | ASTNode::ArrayLiteral(_, span)
+            | ASTNode::ReturnStmt(_, span)
+            | ASTNode::Identifier(_, span)
+            | ASTNode::IntLiteral(_, span)
+            | ASTNode::FloatLiteral(_, span)
+            | ASTNode::CharLiteral(_, span)
+            | ASTNode::StringLiteral(_, span)
+            | ASTNode::Error(span) => *span,
+            ASTNode::Program(instructions) => match (instructions.first(), instructions.last()) {
+                (Some(first), Some(last)) => first.span().to(last.span()),
+                _ => lexer::Span::default(),
+            },
+        }
+    }
+}
+
+// Keywords that start a variable declaration.
+const DECLARATION_KEYWORDS: [&str; 5] = ["const", "int", "float", "char", "string"];
+const PREFIX_OPERATORS: [&str; 8] = ["-", "+", "!", "~", "++", "--", "*", "&"];
+const PREFIX_BINDING_POWER: u8 = 25;
+const POSTFIX_BINDING_POWER: u8 = 27;
+const ASSIGNMENT_OPERATORS: [&str; 11] = ["=", "+=", "-=", "*=", "/=", "%=", "&=", "|=", "^=", "<<=", ">>="];
+
+// Left and right binding power of the binary operators, following the C
+// precedence table from `?:` (loosest) to `*` (tightest). Assignments and
+// `?:` are right associative, so their right power is lower than their left
+// one.
+fn infix_binding_power(operator: &str) -> Option<(u8, u8)> {
+    let binding_power = match operator {
+        op if ASSIGNMENT_OPERATORS.contains(&op) => (2, 1),
+        "?" => (4, 3),
+        "||" => (5, 6),
+        "&&" => (7, 8),
+        "|" => (9, 10),
+        "^" => (11, 12),
+        "&" => (13, 14),
+        "==" | "!=" => (15, 16),
+        "<" | ">" | "<=" | ">=" => (17, 18),
+        "<<" | ">>" => (19, 20),
+        "+" | "-" => (21, 22),
+        "*" | "/" | "%" => (23, 24),
+        _ => return None,
+    };
+    Some(binding_power)
 }
 
-pub struct Parser {
-    tokens: Vec<lexer::TokType>,
+// Where a type is being written, which decides whether it names something
+// and which types are allowed.
+#[derive(PartialEq, Clone, Copy)]
+enum TypeContext {
+    Variable,
+    Parameter,
+    Return,
+}
+
+impl TypeContext {
+    fn describe(&self) -> &'static str {
+        match self {
+            TypeContext::Variable | TypeContext::Parameter => "data type",
+            TypeContext::Return => "return type",
+        }
+    }
+
+    fn allowed(&self) -> &'static str {
+        match self {
+            TypeContext::Variable => "variables must be int, float, char or string, or pointers and arrays of them",
+            TypeContext::Parameter => "parameters must be int, float, char or string, or pointers and arrays of them",
+            TypeContext::Return => "functions must return void, int, float, char or string, or pointers to them",
+        }
+    }
+}
+
+// The parser pulls tokens from the lexer one at a time and only keeps the
+// current one and the span of the previous one.
+pub struct Parser<'a> {
+    lexer: lexer::Lexer<'a>,
+    current: lexer::Token,
+    prev_span: lexer::Span,
+    // Number of tokens consumed so far.
     pos: usize,
+    // Whether a function body is being parsed: functions can't be nested.
+    in_function: bool,
+    diagnostics: Vec<Diagnostic>,
 }
 
-impl Parser {
-    fn new(tokens: Vec<lexer::TokType>) -> Self {
+impl<'a> Parser<'a> {
+    fn new(mut lexer: lexer::Lexer<'a>) -> Self {
+        let current = lexer.next().expect("The lexer yields at least EOF");
         Parser {
-            tokens,
+            lexer,
+            current,
+            prev_span: lexer::Span::default(),
             pos: 0,
+            in_function: false,
+            diagnostics: Vec::new(),
         }
     }
 
-   fn cur_token(&mut self) -> lexer::TokType {
-        return self.tokens.get(self.pos).unwrap().clone();
+    fn cur_token(&self) -> lexer::TokType {
+        self.current.kind.clone()
+    }
+
+    fn cur_span(&self) -> lexer::Span {
+        self.current.span
+    }
+
+    // Span of the last consumed token, used to close the span of a node.
+    fn prev_span(&self) -> lexer::Span {
+        self.prev_span
     }
 
     fn parser_advance(&mut self) {
-        if self.pos < self.tokens.len() {
-            self.pos = self.pos + 1;
+        if self.current.kind != lexer::TokType::EOF {
+            self.prev_span = self.current.span;
+            self.current = self.lexer.next().expect("The lexer yields EOF last");
+            self.pos += 1;
+        }
+    }
+
+    // Consumes the first `len` characters of the current operator token and
+    // leaves the rest of it as the current token.
+    fn split_token(&mut self, len: u32) {
+        let token = &mut self.current;
+        if let lexer::TokType::OPERATOR(op) = &token.kind {
+            token.kind = lexer::TokType::OPERATOR(op[len as usize..].to_string());
+            self.prev_span = lexer::Span { end: token.span.start + len, end_line: token.span.start_line, end_col: token.span.start_col + len, ..token.span };
+            token.span.start += len;
+            token.span.start_col += len;
+            self.pos += 1;
         }
     }
 
-    fn expected_token(&mut self, expected: lexer::TokType) {
+    fn unexpected(&self, message: String) -> Diagnostic {
+        Diagnostic::error(diagnostic::UNEXPECTED_TOKEN, message, self.cur_span())
+    }
+
+    fn expected_token(&mut self, expected: lexer::TokType) -> Result<(), Diagnostic> {
         if self.cur_token() != expected {
-            panic!("Expected {:?} but got {:?}", expected, self.cur_token());
-        } else {
-            self.parser_advance();
+            return Err(self.unexpected(format!("Expected {expected}, found {}", self.cur_token())));
+        }
+        self.parser_advance();
+        Ok(())
+    }
+
+    // Panic-mode recovery: skip tokens up to the end of the current
+    // instruction (`;`), the end of the enclosing block (`}`) or the start of
+    // the next function.
+    fn synchronize(&mut self) {
+        loop {
+            match self.cur_token() {
+                lexer::TokType::EOF | lexer::TokType::RBRACE(_) => return,
+                lexer::TokType::SEMICOLON(_) => {
+                    self.parser_advance();
+                    return;
+                }
+                lexer::TokType::KEYWORD(keyword) if keyword == "fn" => return,
+                _ => self.parser_advance(),
+            }
+        }
+    }
+
+    // Parses an instruction, recording the diagnostic and recovering instead
+    // of giving up when it is malformed.
+    fn parse_recovering(&mut self) -> ASTNode {
+        let start = self.pos;
+        let start_span = self.cur_span();
+        match self.parse_instruction() {
+            Ok(instr) => instr,
+            Err(diagnostic) => {
+                self.diagnostics.push(diagnostic);
+                self.synchronize();
+                if self.pos == start {
+                    self.parser_advance();
+                }
+                ASTNode::Error(start_span)
+            }
         }
     }
 
-    fn parse_instruction(&mut self) -> ASTNode {
-        let int_keyword   = lexer::TokType::KEYWORD("int".to_string());
-        let float_keyword = lexer::TokType::KEYWORD("float".to_string());
-        let char_keyword  = lexer::TokType::KEYWORD("char".to_string());
-        let str_keyword   = lexer::TokType::KEYWORD("string".to_string());
-        let data_keyword: Vec<lexer::TokType> = Vec::from([int_keyword, float_keyword, char_keyword, str_keyword]);
+    fn parse_instruction(&mut self) -> Result<ASTNode, Diagnostic> {
         let cur_token: lexer::TokType = self.cur_token();
 
-        if data_keyword.contains(&cur_token) {
-            return self.parse_var();
+        if cur_token.as_keyword().is_some_and(|keyword| DECLARATION_KEYWORDS.contains(&keyword.as_str())) {
+            self.parse_var()
         }
         else if cur_token == lexer::TokType::KEYWORD("fn".to_string()) {
-            return self.parse_func();
+            if self.in_function {
+                return self.parse_nested_func();
+            }
+            self.parse_func()
         }
         else if cur_token == lexer::TokType::KEYWORD("return".to_string()) {
-            return self.parse_return_stmt();
+            self.parse_return_stmt()
         }
         else if cur_token == lexer::TokType::KEYWORD("if".to_string()) {
-            return self.parse_if_stmt();
+            self.parse_if_stmt()
         }
         else if cur_token == lexer::TokType::KEYWORD("while".to_string()) {
-            return self.parse_while_stmt();
+            self.parse_while_stmt()
         }
         else if cur_token == lexer::TokType::KEYWORD("do".to_string()) {
-            return self.parse_do_while_stmt();
+            self.parse_do_while_stmt()
         }
         else if cur_token == lexer::TokType::KEYWORD("for".to_string()) {
-            return self.parse_for_statement();
+            self.parse_for_statement()
         } else {
-            return self.parse_assignment();
+            self.parse_expression_stmt()
         }
     }
 
-    //TODO the right term of an assignment could be an operation, so I need to check it
-    fn parse_assignment(&mut self) -> ASTNode {
-        let left_term = match self.cur_token() {
-            lexer::TokType::IDENTIFIER(ident) => Box::new(ASTNode::Identifier(ident)),
-            _ => panic!("Not a valid left term for the assignment, got {:?}", self.cur_token()),
-        };
-        self.parser_advance();
-        self.expected_token(lexer::TokType::OPERATOR("=".to_string()));
-        let right_term = Box::new(self.parse_term());
-        self.expected_token(lexer::TokType::SEMICOLON(';'));
-        ASTNode::Assignment { left_term, right_term }
-    }
-
-    fn parse_unary_operation(&mut self) -> ASTNode {
-        let prefix_unary_operator: Vec<&str> = Vec::from(["-", "!", "~", "++", "--"]);
-        let mut operator: lexer::TokType = lexer::TokType::OPERATOR("".to_string());
-        let mut operand: ASTNode = ASTNode::Identifier("".to_string());
-        let mut found: bool = false;
-        for prefix_operator in prefix_unary_operator {
-            if self.cur_token() == lexer::TokType::OPERATOR(prefix_operator.to_string()) {
-                operator = self.cur_token();
-                self.parser_advance();
-                operand = self.parse_term();
-                found = true;
-            }
-        }
-
-        if !found {
-            panic!("Expected a unary operator but got {:?}", self.cur_token());
-        }
+    fn parse_expression_stmt(&mut self) -> Result<ASTNode, Diagnostic> {
+        let expression = self.parse_expression()?;
+        self.expected_token(lexer::TokType::SEMICOLON(';'))?;
+        Ok(expression)
+    }
 
-        ASTNode::UnaryOP { operator, operand: Box::new(operand) }
+    fn parse_expression(&mut self) -> Result<ASTNode, Diagnostic> {
+        self.parse_expression_bp(0)
     }
-    //TODO check for multiple operations and for precedence
-    fn parse_binary_operation(&mut self) -> ASTNode {
-        let binary_operators: Vec<&str> = Vec::from(["==", "!=", ">", "<", ">=", "<=",
-                                                 "&&", "||", "*", "/", "%", "+",
-                                                 "-", "&", "|", ">>", "<<", "=",
-                                                 "+=", "-=", "*=", "/=", "%=", "&=",
-                                                 "|=", "<<=", ">>="
-                                        ]);
-        let left_op = self.parse_term();
-        let op_token = self.cur_token();
-        let mut right_op: ASTNode = ASTNode::Identifier("".to_string());
-        let mut found: bool = false;
-        for operator in binary_operators {
-            if op_token == lexer::TokType::OPERATOR(operator.to_string()) {
+
+    // Precedence climbing: keep folding operators into `left` as long as
+    // they bind at least as tightly as `min_bp`.
+    fn parse_expression_bp(&mut self, min_bp: u8) -> Result<ASTNode, Diagnostic> {
+        let mut left = self.parse_prefix()?;
+        loop {
+            let op_token = self.cur_token();
+            let op_span = self.cur_span();
+            let operator = match &op_token {
+                lexer::TokType::LSQUARE(_) => {
+                    if POSTFIX_BINDING_POWER < min_bp {
+                        break;
+                    }
+                    self.parser_advance();
+                    let index = self.parse_expression()?;
+                    self.expected_token(lexer::TokType::RSQUARE(']'))?;
+                    let span = left.span().to(self.prev_span());
+                    left = ASTNode::Index { array: Box::new(left), index: Box::new(index), span };
+                    continue;
+                }
+                lexer::TokType::OPERATOR(operator) => operator.clone(),
+                _ => break,
+            };
+            if operator == "++" || operator == "--" {
+                if POSTFIX_BINDING_POWER < min_bp {
+                    break;
+                }
                 self.parser_advance();
-                right_op = self.parse_term();
-                found = true;
+                let span = left.span().to(op_span);
+                left = ASTNode::PostfixOP { operator: op_token, operand: Box::new(left), span };
+                continue;
             }
+            let Some((l_bp, r_bp)) = infix_binding_power(&operator) else {
+                break;
+            };
+            if l_bp < min_bp {
+                break;
+            }
+            self.parser_advance();
+            if operator == "?" {
+                // Anything can go between `?` and `:`, as in C.
+                let then_branch = self.parse_expression()?;
+                self.expected_token(lexer::TokType::OPERATOR(":".to_string()))?;
+                let else_branch = self.parse_expression_bp(r_bp)?;
+                let span = left.span().to(else_branch.span());
+                left = ASTNode::TernaryOP {
+                    condition: Box::new(left),
+                    then_branch: Box::new(then_branch),
+                    else_branch: Box::new(else_branch),
+                    span,
+                };
+                continue;
+            }
+            let right = self.parse_expression_bp(r_bp)?;
+            let span = left.span().to(right.span());
+            left = if ASSIGNMENT_OPERATORS.contains(&operator.as_str()) {
+                if !left.is_lvalue() {
+                    return Err(Diagnostic::error(diagnostic::UNEXPECTED_TOKEN, "Not a valid left term for the assignment".to_string(), left.span()));
+                }
+                ASTNode::Assignment { operator: op_token, left_term: Box::new(left), right_term: Box::new(right), span }
+            } else {
+                ASTNode::BinaryOP { operator: op_token, left: Box::new(left), right: Box::new(right), span }
+            };
         }
-        if !found {
-            panic!("Expected a binary operator but got {:?}", self.cur_token());
-        }
+        Ok(left)
+    }
 
-        ASTNode::BinaryOP { operator: (op_token), left: Box::new(left_op), right: Box::new(right_op) }
+    fn parse_prefix(&mut self) -> Result<ASTNode, Diagnostic> {
+        if let lexer::TokType::OPERATOR(op) = self.cur_token() {
+            if !PREFIX_OPERATORS.contains(&op.as_str()) {
+                return Err(self.unexpected(format!("Expected a unary operator, found {}", self.cur_token())));
+            }
+            let operator = self.cur_token();
+            let op_span = self.cur_span();
+            self.parser_advance();
+            let operand = self.parse_expression_bp(PREFIX_BINDING_POWER)?;
+            let span = op_span.to(operand.span());
+            return Ok(ASTNode::UnaryOP { operator, operand: Box::new(operand), span });
+        }
+        self.parse_term()
     }
 
-    fn parse_term(&mut self) -> ASTNode {
+    fn parse_term(&mut self) -> Result<ASTNode, Diagnostic> {
+        let span = self.cur_span();
         let term = match self.cur_token() {
-            lexer::TokType::IDENTIFIER(ident) => ASTNode::Identifier(ident),
-            lexer::TokType::STRING(string) => ASTNode::StringLiteral(string),
-            lexer::TokType::NUMBER(num) => ASTNode::IntLiteral(num.parse::<i64>().unwrap()),
-            _ => panic!("Expected a term or initializer but got {:?}", self.cur_token())
+            lexer::TokType::IDENTIFIER(ident) => {
+                self.parser_advance();
+                if self.cur_token() == lexer::TokType::LPAREN('(') {
+                    return self.parse_call(ident, span);
+                }
+                return Ok(ASTNode::Identifier(ident, span));
+            }
+            lexer::TokType::STRING(mut string) => {
+                // Adjacent literals are joined, as in C: "ab" "cd" is "abcd".
+                self.parser_advance();
+                while let lexer::TokType::STRING(next) = self.cur_token() {
+                    string.push_str(&next);
+                    self.parser_advance();
+                }
+                return Ok(ASTNode::StringLiteral(string, span.to(self.prev_span())));
+            }
+            // The lexer already reported malformed and out-of-range literals.
+            lexer::TokType::NUMBER(num) => ASTNode::IntLiteral(lexer::int_value(&num).unwrap_or_default(), span),
+            lexer::TokType::FLOAT(num) => ASTNode::FloatLiteral(num.replace('_', "").parse::<f64>().unwrap_or_default(), span),
+            lexer::TokType::CHAR(ch) => ASTNode::CharLiteral(ch, span),
+            lexer::TokType::LPAREN(_) => {
+                self.parser_advance();
+                let expression = self.parse_expression()?;
+                self.expected_token(lexer::TokType::RPAREN(')'))?;
+                return Ok(expression);
+            }
+            _ => return Err(self.unexpected(format!("Expected a term or initializer, found {}", self.cur_token())))
         };
         self.parser_advance();
-        term
+        Ok(term)
     }
 
-    //TODO parse statements
-    fn parse_return_stmt(&mut self) -> ASTNode {
+    fn parse_call(&mut self, callee: String, callee_span: lexer::Span) -> Result<ASTNode, Diagnostic> {
+        self.expected_token(lexer::TokType::LPAREN('('))?;
+        let mut args: Vec<ASTNode> = Vec::new();
+        while self.cur_token() != lexer::TokType::RPAREN(')') {
+            args.push(self.parse_expression()?);
+            if self.cur_token() != lexer::TokType::RPAREN(')') {
+                self.expected_token(lexer::TokType::COMMA(','))?;
+                // A `,` must be followed by another argument.
+                if self.cur_token() == lexer::TokType::RPAREN(')') {
+                    return Err(self.unexpected(format!("Expected an expression, found {}", self.cur_token())));
+                }
+            }
+        }
+        let span = callee_span.to(self.cur_span());
         self.parser_advance();
-        let term = Box::new(self.parse_term());
-        self.expected_token(lexer::TokType::SEMICOLON(';'));
-        ASTNode::ReturnStmt(term)
+        Ok(ASTNode::Call { callee, args, span })
     }
 
-    fn parse_if_stmt(&mut self) -> ASTNode {
+    //TODO parse statements
+    fn parse_return_stmt(&mut self) -> Result<ASTNode, Diagnostic> {
+        let start = self.cur_span();
         self.parser_advance();
-        self.expected_token(lexer::TokType::LPAREN('('));
-        let condition = match self.cur_token() {
-            lexer::TokType::IDENTIFIER(_ident) => Box::new(self.parse_binary_operation()),
-            lexer::TokType::OPERATOR(_op) => Box::new(self.parse_unary_operation()),
-            _ => panic!("Illegal start of a condition.\nExpected a term or a unary operator but got {:?}", self.cur_token()),
+        let value = if self.cur_token() == lexer::TokType::SEMICOLON(';') {
+            None
+        } else {
+            Some(Box::new(self.parse_expression()?))
         };
-        self.expected_token(lexer::TokType::RPAREN(')'));
-        let if_branch = Box::new(self.parse_block(false));
+        self.expected_token(lexer::TokType::SEMICOLON(';'))?;
+        Ok(ASTNode::ReturnStmt(value, start.to(self.prev_span())))
+    }
+
+    fn parse_if_stmt(&mut self) -> Result<ASTNode, Diagnostic> {
+        let start = self.cur_span();
+        self.parser_advance();
+        self.expected_token(lexer::TokType::LPAREN('('))?;
+        let condition = Box::new(self.parse_expression()?);
+        self.expected_token(lexer::TokType::RPAREN(')'))?;
+        let if_branch = Box::new(self.parse_body()?);
         let else_branch = if self.cur_token() == lexer::TokType::KEYWORD("else".to_string()) {
             self.parser_advance();
-            Some(Box::new(self.parse_block(false)))
+            if self.cur_token() == lexer::TokType::KEYWORD("if".to_string()) {
+                Some(Box::new(self.parse_if_stmt()?))
+            } else {
+                Some(Box::new(self.parse_body()?))
+            }
         } else {
             None
         };
-        ASTNode::IfStmt { condition, if_branch , else_branch }
+        Ok(ASTNode::IfStmt { condition, if_branch, else_branch, span: start.to(self.prev_span()) })
     }
 
-    fn parse_while_stmt(&mut self) -> ASTNode {
+    fn parse_while_stmt(&mut self) -> Result<ASTNode, Diagnostic> {
+        let start = self.cur_span();
         self.parser_advance();
-        self.expected_token(lexer::TokType::LPAREN('('));
-        let condition = match self.cur_token() {
-            lexer::TokType::IDENTIFIER(_ident) => Box::new(self.parse_binary_operation()),
-            lexer::TokType::OPERATOR(_op) => Box::new(self.parse_unary_operation()),
-            _ => panic!("Illegal start of a condtion.\nExpected a term or an operator but got {:?}", self.cur_token()),
-        };
-        self.expected_token(lexer::TokType::RPAREN(')'));
-        let body = Box::new(ASTNode::Block(self.parse_block(false)));
-        ASTNode::WhileStmt { condition, body }
+        self.expected_token(lexer::TokType::LPAREN('('))?;
+        let condition = Box::new(self.parse_expression()?);
+        self.expected_token(lexer::TokType::RPAREN(')'))?;
+        let body = Box::new(self.parse_body()?);
+        Ok(ASTNode::WhileStmt { condition, body, span: start.to(self.prev_span()) })
     }
 
-    fn parse_do_while_stmt(&mut self) -> ASTNode {
+    fn parse_do_while_stmt(&mut self) -> Result<ASTNode, Diagnostic> {
+        let start = self.cur_span();
         self.parser_advance();
-        let body = Box::new(ASTNode::Block(self.parse_block(false)));
-        self.expected_token(lexer::TokType::KEYWORD("while".to_string()));
-        self.expected_token(lexer::TokType::LPAREN('('));
-        let condition = match self.cur_token() {
-            lexer::TokType::IDENTIFIER(_ident) => Box::new(self.parse_binary_operation()),
-            lexer::TokType::OPERATOR(_op) => Box::new(self.parse_unary_operation()),
-            _ => panic!("Illegal start of a condition.\nExpected a term or a unary operator but got {:?}", self.cur_token()),
-        };
-        self.expected_token(lexer::TokType::RPAREN(')'));
-        self.expected_token(lexer::TokType::SEMICOLON(';'));
-        ASTNode::DoWhileStmt { body, condition }
+        let body = Box::new(self.parse_body()?);
+        self.expected_token(lexer::TokType::KEYWORD("while".to_string()))?;
+        self.expected_token(lexer::TokType::LPAREN('('))?;
+        let condition = Box::new(self.parse_expression()?);
+        self.expected_token(lexer::TokType::RPAREN(')'))?;
+        self.expected_token(lexer::TokType::SEMICOLON(';'))?;
+        Ok(ASTNode::DoWhileStmt { body, condition, span: start.to(self.prev_span()) })
     }
 
-    fn parse_for_statement(&mut self) -> ASTNode {
-        todo!("Parse for statement");
+    // for (init; condition; step) body, where every clause may be empty and
+    // the initialiser is either a declaration or an expression.
+    fn parse_for_statement(&mut self) -> Result<ASTNode, Diagnostic> {
+        let start = self.cur_span();
+        self.parser_advance();
+        self.expected_token(lexer::TokType::LPAREN('('))?;
+        let init = match self.cur_token() {
+            lexer::TokType::SEMICOLON(_) => {
+                self.parser_advance();
+                None
+            }
+            lexer::TokType::KEYWORD(keyword) if DECLARATION_KEYWORDS.contains(&keyword.as_str()) => Some(Box::new(self.parse_var()?)),
+            _ => Some(Box::new(self.parse_expression_stmt()?)),
+        };
+        let condition = if self.cur_token() == lexer::TokType::SEMICOLON(';') {
+            None
+        } else {
+            Some(Box::new(self.parse_expression()?))
+        };
+        self.expected_token(lexer::TokType::SEMICOLON(';'))?;
+        let step = if self.cur_token() == lexer::TokType::RPAREN(')') {
+            None
+        } else {
+            Some(Box::new(self.parse_expression()?))
+        };
+        self.expected_token(lexer::TokType::RPAREN(')'))?;
+        let body = Box::new(self.parse_body()?);
+        Ok(ASTNode::ForStmt { init, condition, step, body, span: start.to(self.prev_span()) })
     }
 
 
     //TODO function to control the variable declaration
-    fn parse_var(&mut self) -> ASTNode {
-        let var_type: String = self.cur_token().as_keyword().unwrap().to_string();
-        self.parser_advance();
-        let mut name: String = String::new();
-        match self.cur_token() {
-            lexer::TokType::IDENTIFIER(str) => name.push_str(&str),
-            _ => panic!("Expected an identifier token but got {:?}", self.cur_token()),
-        };
-        self.parser_advance();
+    fn parse_var(&mut self) -> Result<ASTNode, Diagnostic> {
+        let start = self.cur_span();
+        let (var_type, name) = self.parse_type(TypeContext::Variable)?;
+        let (name, _) = name.expect("Variables always have a name");
         let initializer = if self.cur_token() == lexer::TokType::OPERATOR("=".to_string()) {
             self.parser_advance();
-            self.parse_term()
+            Some(Box::new(self.parse_initializer()?))
+        } else if matches!(var_type, Type::Array(_, Some(_))) {
+            // A sized array can be declared without initialising it, unless
+            // it is const (then `var_type` is a `Type::Const`).
+            None
         } else {
-            panic!("Expected an initializer but found {:?}", self.cur_token());
+            return Err(self.unexpected(format!("Expected an initializer, found {}", self.cur_token())));
         };
-        self.expected_token(lexer::TokType::SEMICOLON(';'));
-        ASTNode::VarDec { var_type, name, initializer: Some(Box::new(initializer)) }
+        self.expected_token(lexer::TokType::SEMICOLON(';'))?;
+        Ok(ASTNode::VarDec { var_type, name, initializer, span: start.to(self.prev_span()) })
     }
 
-    fn parse_func(&mut self) -> ASTNode {
+    // Either an expression or a brace initialiser list, whose elements are
+    // initialisers themselves so that `{{1, 2}, {3, 4}}` works. A trailing
+    // comma is allowed, as in C.
+    fn parse_initializer(&mut self) -> Result<ASTNode, Diagnostic> {
+        if self.cur_token() != lexer::TokType::LBRACE('{') {
+            return self.parse_expression();
+        }
+        let start = self.cur_span();
         self.parser_advance();
-        let name = match self.cur_token() {
-            lexer::TokType::IDENTIFIER(ident) => String::from(ident),
-            _ => panic!("Expected an identifier token but got {:?}", self.cur_token())
+        let mut elements: Vec<ASTNode> = Vec::new();
+        while self.cur_token() != lexer::TokType::RBRACE('}') {
+            elements.push(self.parse_initializer()?);
+            if self.cur_token() != lexer::TokType::RBRACE('}') {
+                self.expected_token(lexer::TokType::COMMA(','))?;
+            }
+        }
+        self.parser_advance();
+        Ok(ASTNode::ArrayLiteral(elements, start.to(self.prev_span())))
+    }
+
+    // Parses a type the way it is written in `context`: an optional `const`,
+    // a primitive type, the declared name (not for return types) and then
+    // the `*` and `[len]` suffixes, as in `const int a*` or `int m[2][3]`.
+    // `const` applies to the whole declaration, so `const int a*` is a
+    // constant pointer.
+    fn parse_type(&mut self, context: TypeContext) -> Result<(Type, Option<(String, lexer::Span)>), Diagnostic> {
+        let is_const = self.cur_token() == lexer::TokType::KEYWORD("const".to_string());
+        if is_const {
+            // A returned value is a copy, so it can't be const.
+            if context == TypeContext::Return {
+                return Err(Diagnostic::error(diagnostic::INVALID_TYPE, format!("Not a valid {}, got `const`", context.describe()), self.cur_span())
+                    .with_note(context.allowed()));
+            }
+            self.parser_advance();
+        }
+        let type_span = self.cur_span();
+        let base = match self.cur_token() {
+            lexer::TokType::KEYWORD(keyword) => match Type::from_name(&keyword) {
+                Some(base) => base,
+                None => return Err(Diagnostic::error(diagnostic::INVALID_TYPE, format!("Not a valid {}, got {keyword}", context.describe()), type_span)
+                    .with_note(context.allowed())),
+            },
+            _ => return Err(self.unexpected(format!("Expected a type, found {}", self.cur_token()))),
         };
         self.parser_advance();
-        self.expected_token(lexer::TokType::LPAREN('('));
-        let mut params: Vec<(String, String)> = Vec::new();
-        let valid_param_types: Vec<&str> = Vec::from(["int", "float", "char", "string"]);
-        while self.cur_token() != lexer::TokType::RPAREN(')') {
-            let mut param_type: String = String::new();
+
+        let name = if context == TypeContext::Return {
+            None
+        } else {
+            let span = self.cur_span();
             match self.cur_token() {
-                lexer::TokType::KEYWORD(data_type) => {
-                    if !valid_param_types.contains(&data_type.as_str()) {
-                        panic!("Not a valid data type, got {data_type}")
-                    }
-                    param_type.push_str(&data_type);
+                lexer::TokType::IDENTIFIER(ident) => {
+                    self.parser_advance();
+                    Some((ident, span))
                 }
-                _ => panic!("Expected a keyword but got {:?}", self.cur_token()),
-            };
-            self.parser_advance();
-            let mut param_name: String = String::new();
+                _ => return Err(self.unexpected(format!("Expected an identifier, found {}", self.cur_token()))),
+            }
+        };
+
+        let mut ty = base.clone();
+        loop {
             match self.cur_token() {
-                lexer::TokType::IDENTIFIER(par_name) => param_name.push_str(&par_name),
-                _ => panic!{"Expected an identifier but got {:?}", self.cur_token()},
-            };
-            params.push(( param_type, param_name ));
+                lexer::TokType::OPERATOR(op) if op == "*" => self.parser_advance(),
+                // `int a*= b` is lexed as `*=`: take the `*`, leave the `=`.
+                lexer::TokType::OPERATOR(op) if op == "*=" => self.split_token(1),
+                _ => break,
+            }
+            ty = Type::Pointer(Box::new(ty));
+        }
+        let mut lengths: Vec<Option<usize>> = Vec::new();
+        while self.cur_token() == lexer::TokType::LSQUARE('[') {
             self.parser_advance();
-            if self.cur_token() != lexer::TokType::RPAREN(')') {
-                self.expected_token(lexer::TokType::COMMA(','));
+            let len = match self.cur_token() {
+                lexer::TokType::NUMBER(num) => match lexer::int_value(&num).and_then(|len| usize::try_from(len).ok()) {
+                    Some(len) => Some(len),
+                    None => return Err(Diagnostic::error(diagnostic::INVALID_TYPE, format!("Not a valid array length, got {num}"), self.cur_span())),
+                },
+                _ => None,
+            };
+            if len.is_some() {
+                self.parser_advance();
             }
+            self.expected_token(lexer::TokType::RSQUARE(']'))?;
+            lengths.push(len);
+        }
+        // `int m[2][3]` is an array of 2 arrays of 3 ints.
+        for len in lengths.iter().rev() {
+            ty = Type::Array(Box::new(ty), *len);
+        }
+
+        let suffixed = ty != base;
+        if (base == Type::Void && (context != TypeContext::Return || suffixed))
+            || (context == TypeContext::Return && !lengths.is_empty()) {
+            let span = if suffixed { type_span.to(self.prev_span()) } else { type_span };
+            return Err(Diagnostic::error(diagnostic::INVALID_TYPE, format!("Not a valid {}, got {ty}", context.describe()), span)
+                .with_note(context.allowed()));
         }
+        if is_const {
+            ty = Type::Const(Box::new(ty));
+        }
+        Ok((ty, name))
+    }
+
+    fn parse_func(&mut self) -> Result<ASTNode, Diagnostic> {
+        let doc = self.current.doc.clone();
+        self.parser_advance();
+        let name_span = self.cur_span();
+        let name = match self.cur_token() {
+            lexer::TokType::IDENTIFIER(ident) => ident,
+            _ => return Err(self.unexpected(format!("Expected an identifier, found {}", self.cur_token())))
+        };
         self.parser_advance();
-        self.expected_token(lexer::TokType::OPERATOR("->".to_string()));
-        let mut ret_type: String = String::new();
-        let valid_ret_types: Vec<&str> = Vec::from(["void", "int", "float", "char", "string"]);
-        match self.cur_token() {
-            lexer::TokType::KEYWORD(return_type) => {
-                if !valid_ret_types.contains(&return_type.as_str()) {
-                    panic!("Not a valid return type, got {return_type}");
+        self.expected_token(lexer::TokType::LPAREN('('))?;
+        let mut params: Vec<(Type, String, lexer::Span)> = Vec::new();
+        while self.cur_token() != lexer::TokType::RPAREN(')') {
+            let (param_type, param_name) = self.parse_type(TypeContext::Parameter)?;
+            let (param_name, param_span) = param_name.expect("Parameters always have a name");
+            params.push(( param_type, param_name, param_span ));
+            if self.cur_token() != lexer::TokType::RPAREN(')') {
+                self.expected_token(lexer::TokType::COMMA(','))?;
+                if self.cur_token() == lexer::TokType::RPAREN(')') {
+                    return Err(self.unexpected(format!("Expected a parameter, found {}", self.cur_token())));
                 }
-                ret_type.push_str(&return_type);
             }
-            _ => panic!{"Expected a keyword but got {:?}", self.cur_token()}
         }
         self.parser_advance();
-        let mut need_return: bool = false;
-        if !ret_type.contains("void") {
-            need_return = true;
+        self.expected_token(lexer::TokType::OPERATOR("->".to_string()))?;
+        let (ret_type, _) = self.parse_type(TypeContext::Return)?;
+        let enclosing = std::mem::replace(&mut self.in_function, true);
+        let body = self.parse_block();
+        self.in_function = enclosing;
+        let body = Box::new(body?);
+        let end_span = self.prev_span();
+        Ok(ASTNode::FuncDec { doc, name, params, ret_type, body, span: name_span, end_span })
+    }
+
+    // A function declared inside another one is still parsed, so that its
+    // body doesn't turn into a cascade of errors, but it is left out of the
+    // tree.
+    fn parse_nested_func(&mut self) -> Result<ASTNode, Diagnostic> {
+        let start = self.cur_span();
+        self.diagnostics.push(Diagnostic::error(diagnostic::UNEXPECTED_TOKEN, "Functions can only be declared at the top level".to_string(), start)
+            .with_note("move this function out of the enclosing one"));
+        self.parse_func()?;
+        Ok(ASTNode::Error(start.to(self.prev_span())))
+    }
+
+    // Body of an if/else, while, do or for: either a braced block or a single
+    // instruction, always represented as a Block.
+    fn parse_body(&mut self) -> Result<ASTNode, Diagnostic> {
+        if self.cur_token() == lexer::TokType::LBRACE('{') {
+            return self.parse_block();
         }
-        let body = Box::new(ASTNode::Block(self.parse_block(need_return)));
-        ASTNode::FuncDec { name, params, ret_type, body }
+        let instr = self.parse_instruction()?;
+        let span = instr.span();
+        Ok(ASTNode::Block(vec![instr], span))
     }
 
-    fn parse_block(&mut self, need_return: bool ) -> Vec<ASTNode> {
-        self.expected_token(lexer::TokType::LBRACE('{'));
+    fn parse_block(&mut self) -> Result<ASTNode, Diagnostic> {
+        let start = self.cur_span();
+        self.expected_token(lexer::TokType::LBRACE('{'))?;
         let mut block: Vec<ASTNode> = Vec::new();
-        let mut return_keyword: bool = false;
-        while self.cur_token() != lexer::TokType::RBRACE('}') && !return_keyword {
-            if self.cur_token() == lexer::TokType::KEYWORD("return".to_string()) {
-                return_keyword = true;
-            }
-            let instr = self.parse_instruction();
+        while self.cur_token() != lexer::TokType::RBRACE('}') && self.cur_token() != lexer::TokType::EOF {
+            let instr = self.parse_recovering();
             block.push(instr);
         }
 
-        if return_keyword {
-            while self.cur_token() != lexer::TokType::RBRACE('}') {
-                self.parser_advance();
+        self.expected_token(lexer::TokType::RBRACE('}'))?;
+        Ok(ASTNode::Block(block, start.to(self.prev_span())))
+    }
+
+    //TODO function to control the block
+}
+
+/// Parses the tokens pulled from `lexer`, returning the program together
+/// with every lexical and syntax error found, in source order. Instructions
+/// that could not be parsed are kept in the tree as `ASTNode::Error`. Syntax
+/// errors at the same place as a lexical error are dropped, as they would
+/// only repeat it.
+pub fn parse_program(lexer: lexer::Lexer) -> (ASTNode, Vec<Diagnostic>) {
+    let mut parser = Parser::new(lexer);
+    let mut program: Vec<ASTNode> = Vec::new();
+    while parser.cur_token() != lexer::TokType::EOF {
+        if let lexer::TokType::RBRACE(_) = parser.cur_token() {
+            // A stray `}` is usually the end of a function whose header failed
+            // to parse, so only report it when nothing else went wrong.
+            if parser.diagnostics.is_empty() {
+                parser.diagnostics.push(parser.unexpected("Unexpected `}` outside of a block".to_string()));
             }
+            parser.parser_advance();
+            continue;
         }
+        program.push(parser.parse_recovering());
+    }
+    let mut diagnostics = parser.lexer.take_diagnostics();
+    let syntax_errors: Vec<Diagnostic> = parser.diagnostics.into_iter()
+        .filter(|syntax_error| !diagnostics.iter().any(|lexical_error| lexical_error.span.overlaps(syntax_error.span)))
+        .collect();
+    diagnostics.extend(syntax_errors);
+    diagnostics.sort_by_key(|diagnostic| diagnostic.span.start);
+    (ASTNode::Program(program), diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> (ASTNode, Vec<Diagnostic>) {
+        parse_program(lexer::Lexer::new(source, 0, &lexer::Options::default()))
+    }
 
-        if need_return && !return_keyword {
-            panic!("Expected a return statement");
+    // The expression as a fully parenthesised prefix form, e.g. `(+ a b)`.
+    fn sexpr(node: &ASTNode) -> String {
+        let op = |operator: &lexer::TokType| operator.as_operator().cloned().unwrap_or_default();
+        match node {
+            ASTNode::Identifier(name, _) => name.clone(),
+            ASTNode::IntLiteral(value, _) => value.to_string(),
+            ASTNode::UnaryOP { operator, operand, .. } => format!("({} {})", op(operator), sexpr(operand)),
+            ASTNode::PostfixOP { operator, operand, .. } => format!("({} {})", sexpr(operand), op(operator)),
+            ASTNode::BinaryOP { operator, left, right, .. } | ASTNode::Assignment { operator, left_term: left, right_term: right, .. } => {
+                format!("({} {} {})", op(operator), sexpr(left), sexpr(right))
+            }
+            ASTNode::TernaryOP { condition, then_branch, else_branch, .. } => {
+                format!("(? {} {} {})", sexpr(condition), sexpr(then_branch), sexpr(else_branch))
+            }
+            ASTNode::Index { array, index, .. } => format!("([] {} {})", sexpr(array), sexpr(index)),
+            node => panic!("Unexpected node {node:?}"),
         }
+    }
 
-        self.parser_advance();
-        block
+    // Parses `source` as a single expression statement.
+    fn parse_expression(source: &str) -> String {
+        let (program, diagnostics) = parse(&format!("{source};"));
+        assert!(diagnostics.is_empty(), "{diagnostics:?}");
+        let ASTNode::Program(instructions) = program else { unreachable!() };
+        sexpr(&instructions[0])
     }
 
-    //TODO function to control the block
-}
+    #[test]
+    fn follows_precedence() {
+        assert_eq!(parse_expression("(a + b) * c - d / 2"), "(- (* (+ a b) c) (/ d 2))");
+        assert_eq!(parse_expression("a << 2 | b"), "(| (<< a 2) b)");
+    }
 
-pub fn parse_program(tokens_list: Vec<lexer::TokType>) {
-    let mut parser = Parser::new(tokens_list.clone());
-    while parser.pos < tokens_list.len(){
-        //println!("{:?}", tokens_list);
-        println!("{:#?}", parser.parse_instruction());
+    #[test]
+    fn assignments_are_right_associative() {
+        assert_eq!(parse_expression("x = y = 3"), "(= x (= y 3))");
     }
-}
 
+    #[test]
+    fn postfix_binds_tighter_than_prefix() {
+        assert_eq!(parse_expression("-a[0]"), "(- ([] a 0))");
+        assert_eq!(parse_expression("*p++"), "(* (p ++))");
+    }
+
+    #[test]
+    fn rejects_assignment_to_a_conditional() {
+        let (_, diagnostics) = parse("a ? b : c = d;");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, diagnostic::UNEXPECTED_TOKEN);
+    }
+
+    #[test]
+    fn rejects_trailing_commas_in_calls_and_parameters() {
+        let (_, diagnostics) = parse("one(1,);");
+        assert_eq!(diagnostics[0].message, "Expected an expression, found `)`");
+        let (_, diagnostics) = parse("fn one(int a,) -> int { return a; }");
+        assert_eq!(diagnostics[0].message, "Expected a parameter, found `)`");
+    }
+
+    #[test]
+    fn rejects_const_return_types() {
+        let (_, diagnostics) = parse("fn v() -> const void { return; }");
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].code, diagnostic::INVALID_TYPE);
+    }
+}
@@ -1,23 +1,36 @@
-use std::fs::{self, File};
-use std::io::{self, BufRead, Seek, SeekFrom, Write, Read};
-use std::os::unix::fs::FileExt;
+use std::collections::HashMap;
+use std::fs;
 use std::path::{Path, PathBuf};
+use crate::diagnostic::{self, Diagnostic};
+use crate::lexer::{self, LosslessToken, TokType, Trivia, TriviaKind};
 
 #[derive(Debug)]
 pub struct Preprocessor {
     include_paths: Vec<PathBuf>,
     contents_to_write: String,
-    new_file: File,
+    // Object-like macros: the tokens each name is replaced with.
+    macros: HashMap<String, Vec<LosslessToken>>,
+    // Whether the previous line ended inside a block comment.
+    in_comment: bool,
+    // File being preprocessed, and the number and byte offset of the line
+    // being processed in it, for the spans of diagnostics.
+    file_id: u32,
+    line: u32,
+    offset: u32,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl Preprocessor {
-    pub fn new(file_path: &str) -> Self {
-        let preprocessed_file_path = "src/preprocessed.i";
-        let new_file = File::create(preprocessed_file_path).expect("Should have been able to create the file");
+    pub fn new(file_id: u32) -> Self {
         Preprocessor {
             include_paths: Vec::new(),
             contents_to_write: String::new(),
-            new_file: new_file,
+            macros: HashMap::new(),
+            in_comment: false,
+            file_id,
+            line: 0,
+            offset: 0,
+            diagnostics: Vec::new(),
         }
     }
 
@@ -25,22 +38,129 @@ impl Preprocessor {
         self.include_paths.push(path.as_ref().to_owned());
     }
 
+    /// Diagnostics reported so far, leaving none behind.
+    pub fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+
     fn process_line(&mut self, line: &str) {
-        if line.starts_with('#') {
+        self.line += 1;
+        if !self.in_comment && line.trim_start().starts_with('#') {
             self.handle_directive(line);
+        } else {
+            let expanded = self.expand_line(line);
+            self.contents_to_write.push_str(&expanded);
         }
+        self.offset += line.len() as u32;
+    }
+
+    // Lexes part of the current line, starting `start` bytes into it, with
+    // spans that point into the file.
+    fn tokenize(&mut self, line: &str, start: usize) -> Vec<LosslessToken> {
+        let (mut tokens, _) = lexer::tokenize_lossless(&line[start..], self.file_id, &lexer::Options::default());
+        let column = line[..start].chars().count() as u32;
+        for token in &mut tokens {
+            let span = &mut token.token.span;
+            span.start += self.offset + start as u32;
+            span.end += self.offset + start as u32;
+            span.start_line += self.line - 1;
+            span.end_line += self.line - 1;
+            span.start_col += column;
+            span.end_col += column;
+        }
+        // A block comment left open at the end of the line goes on until a
+        // later line closes it.
+        self.in_comment = tokens.iter().flat_map(|token| token.leading.iter().chain(&token.trailing)).any(|trivia| {
+            trivia.kind == TriviaKind::BlockComment && (trivia.text.len() < 4 || !trivia.text.ends_with("*/"))
+        });
+        tokens
     }
 
     fn handle_directive(&mut self, line: &str) {
-        let mut parts = line.split_whitespace();
-        let directive = parts.next().unwrap_or("").trim_start_matches('#');
+        // The `#` is skipped by the lexer as an invalid character.
+        let mut tokens = self.tokenize(line, 0).into_iter();
+        let directive = tokens.next().filter(|token| token.token.kind != TokType::EOF);
+        let Some(directive) = directive else {
+            self.contents_to_write.push_str(line);
+            return;
+        };
 
-        match directive {
+        match directive.text.as_str() {
             "include" => {
-                let filename = parts.next().expect("Should have been able to read the filename").trim_matches(|c| c == '"' || c == '<' || c == '>');
+                self.contents_to_write.push_str(line);
+                let directive_line = line.trim().trim_start_matches('#').trim_start();
+                let filename = directive_line["include".len()..].split_whitespace().next().expect("Should have been able to read the filename").trim_matches(|c| c == '"' || c == '<' || c == '>');
                 self.include_file(filename);
             }
-            _ => {},
+            "define" | "undef" => {
+                // The directive is replaced by an empty line so that the
+                // lines after it keep their numbers.
+                self.contents_to_write.push('\n');
+                let mut tokens: Vec<LosslessToken> = tokens.collect();
+                tokens.pop();
+                let name = match tokens.first().map(|token| &token.token.kind) {
+                    // Keywords can be redefined too, as in C.
+                    Some(TokType::IDENTIFIER(name) | TokType::KEYWORD(name)) => name.clone(),
+                    Some(kind) => {
+                        let error = Diagnostic::error(diagnostic::UNEXPECTED_TOKEN, format!("Expected a macro name, found {kind}"), tokens[0].token.span)
+                            .with_note("macro names are identifiers");
+                        self.diagnostics.push(error);
+                        return;
+                    }
+                    None => {
+                        let error = Diagnostic::error(diagnostic::UNEXPECTED_TOKEN, format!("Expected a macro name after `#{}`", directive.text), directive.token.span);
+                        self.diagnostics.push(error);
+                        return;
+                    }
+                };
+                if directive.text == "define" {
+                    self.macros.insert(name, replacement_list(tokens.split_off(1)));
+                } else {
+                    self.macros.remove(&name);
+                }
+            }
+            _ => self.contents_to_write.push_str(line),
+        }
+    }
+
+    // Replaces the macros used in `line`. Only identifier and keyword tokens
+    // are looked up, so names inside string literals, comments or longer
+    // identifiers are left alone. Block comments can span lines, so the part
+    // of the line that is still inside one is copied as it is.
+    fn expand_line(&mut self, line: &str) -> String {
+        let mut expansion = Expansion::default();
+        let mut code_start = 0;
+        if self.in_comment {
+            match line.find("*/") {
+                Some(end) => {
+                    expansion.text.push_str(&line[..end + 2]);
+                    code_start = end + 2;
+                }
+                None => return line.to_string(),
+            }
+        }
+        let tokens = self.tokenize(line, code_start);
+        self.expand_tokens(&tokens, &mut Vec::new(), &mut expansion);
+        expansion.text
+    }
+
+    // `expanding` holds the macros being replaced: a macro that appears in
+    // its own expansion, directly or through other macros, is kept as it is
+    // instead of being expanded forever, as in C.
+    fn expand_tokens(&self, tokens: &[LosslessToken], expanding: &mut Vec<String>, expansion: &mut Expansion) {
+        for token in tokens {
+            expansion.push_trivia(&token.leading);
+            match &token.token.kind {
+                TokType::IDENTIFIER(name) | TokType::KEYWORD(name) if self.macros.contains_key(name) && !expanding.contains(name) => {
+                    expanding.push(name.clone());
+                    expansion.boundary = true;
+                    self.expand_tokens(&self.macros[name], expanding, expansion);
+                    expansion.boundary = true;
+                    expanding.pop();
+                }
+                _ => expansion.push_token(&token.text),
+            }
+            expansion.push_trivia(&token.trailing);
         }
     }
 
@@ -55,19 +175,128 @@ impl Preprocessor {
         }
     }
 
-    pub fn process_file(&mut self, file_path: &str) {
-        self.add_include_paths("src/");
-        let mut file_to_preprocess = File::open(file_path).expect("Should have been able to open the file");
-        let mut reader = io::BufReader::new(file_to_preprocess);
-        let mut line = String::new();
-        while let Ok(bytes_read) = reader.read_line(&mut line) {
-            if bytes_read == 0 {
-                break;
-            }
-            self.contents_to_write.push_str(line.as_str());
-            self.process_line(line.as_str().trim());
-            line.clear();
+    /// Runs the directives in `contents` and expands its macros, returning
+    /// the text to compile. Directives become empty lines, so every line
+    /// keeps its number.
+    pub fn process(&mut self, contents: &str) -> String {
+        for line in contents.split_inclusive('\n') {
+            self.process_line(line);
+        }
+        std::mem::take(&mut self.contents_to_write)
+    }
+}
+
+// A line being expanded: the text written so far and the last token in it,
+// unless whitespace or a comment came after that token.
+#[derive(Default)]
+struct Expansion {
+    text: String,
+    last_token: Option<String>,
+    // Set where a macro's replacement starts or ends, i.e. where two tokens
+    // that weren't next to each other in the source may meet.
+    boundary: bool,
+}
+
+impl Expansion {
+    fn push_trivia(&mut self, trivia: &[Trivia]) {
+        for trivia in trivia {
+            self.text.push_str(&trivia.text);
+            self.last_token = None;
+        }
+    }
+
+    // Tokens are written as text, which is lexed again later, so a space is
+    // added where the new token would otherwise merge with the previous one:
+    // `-NEG` with `#define NEG -1` gives `- -1`, not `--1`.
+    fn push_token(&mut self, text: &str) {
+        if self.boundary && self.last_token.as_deref().is_some_and(|last| pastes(last, text)) {
+            self.text.push(' ');
         }
-        self.new_file.write(self.contents_to_write.as_bytes());
+        self.text.push_str(text);
+        self.last_token = Some(text.to_string());
+        self.boundary = false;
+    }
+}
+
+// Whether `left` directly followed by `right` is lexed as anything else
+// than these two tokens.
+fn pastes(left: &str, right: &str) -> bool {
+    let (tokens, _) = lexer::tokenize_lossless(&format!("{left}{right}"), 0, &lexer::Options::default());
+    tokens.len() != 3 || tokens[0].text != left || tokens[1].text != right
+}
+
+// The tokens of a macro body, with the whitespace and comments around them
+// dropped and those between them turned into a single space.
+fn replacement_list(tokens: Vec<LosslessToken>) -> Vec<LosslessToken> {
+    let spaced: Vec<bool> = tokens.windows(2).map(|pair| !pair[0].trailing.is_empty() || !pair[1].leading.is_empty()).collect();
+    tokens.into_iter().enumerate().map(|(i, mut token)| {
+        token.leading.clear();
+        token.trailing.clear();
+        if spaced.get(i).copied().unwrap_or(false) {
+            token.trailing.push(Trivia { kind: TriviaKind::Whitespace, text: " ".to_string(), span: token.token.span });
+        }
+        token
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preprocess(source: &str) -> (String, Vec<Diagnostic>) {
+        let mut preprocessor = Preprocessor::new(0);
+        let output = preprocessor.process(source);
+        (output, preprocessor.take_diagnostics())
+    }
+
+    #[test]
+    fn expands_chained_macros() {
+        let (output, diagnostics) = preprocess("#define SIZE 10\n#define DOUBLE SIZE * 2\nint x = DOUBLE;\n");
+        assert_eq!(output, "\n\nint x = 10 * 2;\n");
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn keeps_self_referencing_macros() {
+        let (output, _) = preprocess("#define A B\n#define B A\n#define C C + 1\nint x = A + B + C;\n");
+        assert_eq!(output, "\n\n\nint x = A + B + C + 1;\n");
+    }
+
+    #[test]
+    fn leaves_strings_comments_and_longer_names_alone() {
+        let (output, _) = preprocess("#define X 1\nstring s = \"X\"; // X\n/* X\nX */ int XY = X;\n");
+        assert_eq!(output, "\nstring s = \"X\"; // X\n/* X\nX */ int XY = 1;\n");
+    }
+
+    #[test]
+    fn tracks_comments_opened_by_directives() {
+        let (output, _) = preprocess("#define X 1 /* starts here\nX\n*/ X\n");
+        assert_eq!(output, "\nX\n*/ 1\n");
+    }
+
+    #[test]
+    fn undefines_macros() {
+        let (output, _) = preprocess("#define X 1\nX\n#undef X\nX\n");
+        assert_eq!(output, "\n1\n\nX\n");
+    }
+
+    #[test]
+    fn keeps_expanded_tokens_apart() {
+        let (output, _) = preprocess("#define NEG -1\n#define P +\n#define EMPTY\n5-NEG a+P+b -EMPTY-\n");
+        assert_eq!(output, "\n\n\n5- -1 a+ + +b - -\n");
+    }
+
+    #[test]
+    fn redefines_keywords() {
+        let (output, _) = preprocess("#define int float\nint x;\n");
+        assert_eq!(output, "\nfloat x;\n");
+    }
+
+    #[test]
+    fn reports_invalid_macro_names() {
+        let (output, diagnostics) = preprocess("#define\n#define 5 x\n");
+        assert_eq!(output, "\n\n");
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!((diagnostics[1].span.start, diagnostics[1].span.start_line, diagnostics[1].span.start_col), (16, 2, 9));
     }
 }
@@ -0,0 +1,264 @@
+use std::collections::BTreeMap;
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::lexer::Span;
+use crate::source::SourceMap;
+
+const TAB_WIDTH: usize = 4;
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const YELLOW: &str = "\x1b[1;33m";
+const BLUE: &str = "\x1b[1;34m";
+
+/// Underline of (part of) a span on a single source line.
+struct Mark {
+    start: usize,
+    end: usize,
+    primary: bool,
+    label: Option<String>,
+}
+
+/// Prints diagnostics rustc-style: the header, a `-->` location, and the
+/// offending source lines with `^^^` under the primary span and `---` under
+/// secondary labels. The plain mode emits no escape codes, the colour mode
+/// is meant for terminals.
+pub struct Renderer<'a> {
+    sources: &'a SourceMap,
+    colour: bool,
+}
+
+impl<'a> Renderer<'a> {
+    pub fn plain(sources: &'a SourceMap) -> Self {
+        Renderer { sources, colour: false }
+    }
+
+    pub fn coloured(sources: &'a SourceMap) -> Self {
+        Renderer { sources, colour: true }
+    }
+
+    fn paint(&self, style: &str, text: &str) -> String {
+        if self.colour && !text.is_empty() {
+            format!("{style}{text}{RESET}")
+        } else {
+            text.to_string()
+        }
+    }
+
+    fn severity_style(&self, severity: Severity) -> &'static str {
+        match severity {
+            Severity::Error => RED,
+            Severity::Warning => YELLOW,
+        }
+    }
+
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        let severity_style = self.severity_style(diagnostic.severity);
+        let file = self.sources.get(diagnostic.span.file_id);
+        let mut out = String::new();
+        out.push_str(&self.paint(severity_style, &format!("{}[{}]", diagnostic.severity, diagnostic.code)));
+        out.push_str(&self.paint(BOLD, &format!(": {}", diagnostic.message)));
+        out.push('\n');
+
+        // Group underlines by line; labels in other files are not drawn here.
+        let mut lines: BTreeMap<u32, Vec<Mark>> = BTreeMap::new();
+        self.add_marks(&mut lines, diagnostic.span, true, None);
+        for label in &diagnostic.labels {
+            if label.span.file_id == diagnostic.span.file_id {
+                self.add_marks(&mut lines, label.span, false, Some(label.message.clone()));
+            }
+        }
+
+        let gutter_width = lines.keys().last().map_or(1, |line| line.to_string().len());
+        let gutter = " ".repeat(gutter_width);
+        let bar = self.paint(BLUE, "|");
+        out.push_str(&format!("{gutter}{} {}:{}\n", self.paint(BLUE, "-->"), file.name, diagnostic.span));
+        out.push_str(&format!("{gutter} {bar}\n"));
+
+        let mut previous_line: Option<u32> = None;
+        for (line, marks) in lines.iter_mut() {
+            if previous_line.is_some_and(|previous| *line > previous + 1) {
+                out.push_str(&format!("{}\n", self.paint(BLUE, "...")));
+            }
+            previous_line = Some(*line);
+            let text = file.line(*line).unwrap_or("");
+            let number = self.paint(BLUE, &format!("{line:>gutter_width$}"));
+            out.push_str(format!("{number} {bar} {}", expand_tabs(text)).trim_end());
+            out.push('\n');
+            for row in self.underline_rows(text, marks, severity_style) {
+                out.push_str(format!("{gutter} {bar} {row}").trim_end());
+                out.push('\n');
+            }
+        }
+
+        for note in &diagnostic.notes {
+            out.push_str(&format!("{gutter} {} note: {note}\n", self.paint(BLUE, "=")));
+        }
+        out
+    }
+
+    /// Splits `span` into one mark per line it covers. Only the mark on the
+    /// last line carries the label.
+    fn add_marks(&self, lines: &mut BTreeMap<u32, Vec<Mark>>, span: Span, primary: bool, label: Option<String>) {
+        let file = self.sources.get(span.file_id);
+        let end_line = span.end_line.max(span.start_line);
+        for line in span.start_line..=end_line {
+            let text = file.line(line).unwrap_or("");
+            let line_len = text.chars().count() + 1;
+            let indent = text.chars().take_while(|ch| ch.is_whitespace()).count();
+            let start = if line == span.start_line { span.start_col as usize } else { indent + 1 };
+            let end = if line == end_line { span.end_col as usize } else { line_len };
+            lines.entry(line).or_default().push(Mark {
+                start,
+                end: end.max(start + 1),
+                primary,
+                label: if line == end_line { label.clone() } else { None },
+            });
+        }
+    }
+
+    /// Builds the underline row plus one row per extra label, rustc-style:
+    /// the rightmost label goes next to the underlines and every other label
+    /// is hung below its mark with a `|` connector.
+    fn underline_rows(&self, text: &str, marks: &mut [Mark], severity_style: &str) -> Vec<String> {
+        marks.sort_by_key(|mark| mark.start);
+        let mut underline = String::new();
+        let mut width = 0;
+        for mark in marks.iter() {
+            let start = display_col(text, mark.start);
+            let end = display_col(text, mark.end).max(start + 1);
+            if start < width {
+                continue;
+            }
+            underline.push_str(&" ".repeat(start - width));
+            let (ch, style) = if mark.primary { ('^', severity_style) } else { ('-', BLUE) };
+            underline.push_str(&self.paint(style, &ch.to_string().repeat(end - start)));
+            width = end;
+        }
+
+        let labelled: Vec<&Mark> = marks.iter().filter(|mark| mark.label.is_some()).collect();
+        let mut rows = Vec::new();
+        let Some((last, hanging)) = labelled.split_last() else {
+            rows.push(underline);
+            return rows;
+        };
+        rows.push(format!("{underline} {}", self.paint_label(last, severity_style)));
+        for (index, mark) in hanging.iter().enumerate().rev() {
+            let connectors = |upto: usize| -> (String, usize) {
+                let mut row = String::new();
+                let mut width = 0;
+                for other in &hanging[..upto] {
+                    let col = display_col(text, other.start);
+                    row.push_str(&" ".repeat(col.saturating_sub(width)));
+                    row.push_str(&self.paint(BLUE, "|"));
+                    width = col + 1;
+                }
+                (row, width)
+            };
+            let (row, _) = connectors(index + 1);
+            rows.push(row);
+            let (mut row, width) = connectors(index);
+            row.push_str(&" ".repeat(display_col(text, mark.start).saturating_sub(width)));
+            row.push_str(&self.paint_label(mark, severity_style));
+            rows.push(row);
+        }
+        rows
+    }
+
+    fn paint_label(&self, mark: &Mark, severity_style: &str) -> String {
+        let style = if mark.primary { severity_style } else { BLUE };
+        self.paint(style, mark.label.as_deref().unwrap_or(""))
+    }
+}
+
+fn expand_tabs(text: &str) -> String {
+    text.replace('\t', &" ".repeat(TAB_WIDTH))
+}
+
+/// 0-based display column of the 1-based character column `col`, counting
+/// tabs as `TAB_WIDTH` spaces.
+fn display_col(text: &str, col: usize) -> usize {
+    let chars: Vec<char> = text.chars().collect();
+    let before = col.saturating_sub(1);
+    let mut width = 0;
+    for index in 0..before {
+        width += match chars.get(index) {
+            Some('\t') => TAB_WIDTH,
+            _ => 1,
+        };
+    }
+    width
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::diagnostic;
+
+    // Span in file 0; the renderer only looks at lines and columns.
+    fn span(start_line: u32, start_col: u32, end_line: u32, end_col: u32) -> Span {
+        Span { file_id: 0, start: 0, end: 0, start_line, start_col, end_line, end_col }
+    }
+
+    fn render(contents: &str, diagnostic: &Diagnostic) -> String {
+        let mut sources = SourceMap::new();
+        sources.add_file("test.c", contents.to_string());
+        Renderer::plain(&sources).render(diagnostic)
+    }
+
+    #[test]
+    fn underlines_the_primary_span() {
+        let error = Diagnostic::error(diagnostic::UNDECLARED, "Use of undeclared identifier `x`".to_string(), span(1, 8, 1, 9));
+        assert_eq!(render("return x;\n", &error), "\
+error[E0008]: Use of undeclared identifier `x`
+ --> test.c:1:8
+  |
+1 | return x;
+  |        ^
+");
+    }
+
+    #[test]
+    fn separates_distant_labels_with_a_gap() {
+        let error = Diagnostic::error(diagnostic::MISSING_RETURN, "Not all paths of `f` return a value".to_string(), span(4, 1, 4, 2))
+            .with_label(span(1, 4, 1, 5), "function `f` returns int")
+            .with_note("add a return");
+        assert_eq!(render("fn f() -> int {\n  int a = 1;\n  a = 2;\n}\n", &error), "\
+error[E0003]: Not all paths of `f` return a value
+ --> test.c:4:1
+  |
+1 | fn f() -> int {
+  |    - function `f` returns int
+...
+4 | }
+  | ^
+  = note: add a return
+");
+    }
+
+    #[test]
+    fn expands_tabs_before_the_underline() {
+        let error = Diagnostic::error(diagnostic::UNDECLARED, "Use of undeclared identifier `y`".to_string(), span(1, 3, 1, 4));
+        assert_eq!(render("\t\ty;\n", &error), "\
+error[E0008]: Use of undeclared identifier `y`
+ --> test.c:1:3
+  |
+1 |         y;
+  |         ^
+");
+    }
+
+    #[test]
+    fn underlines_a_span_running_to_the_end_of_the_file() {
+        let error = Diagnostic::error(diagnostic::UNTERMINATED_COMMENT, "Unterminated block comment".to_string(), span(1, 5, 2, 4));
+        assert_eq!(render("int /* a\nb c", &error), "\
+error[E0012]: Unterminated block comment
+ --> test.c:1:5
+  |
+1 | int /* a
+  |     ^^^^
+2 | b c
+  | ^^^
+");
+    }
+}
@@ -0,0 +1,21 @@
+use crate::diagnostic::Diagnostic;
+use crate::flow;
+use crate::parser::ASTNode;
+use crate::symbols::Resolver;
+use crate::typeck::{TypeChecker, TypeTable};
+
+#[derive(Debug, Default)]
+pub struct Options {
+    pub warn_shadowing: bool,
+}
+
+/// Runs the checks that need the whole program, returning the type of every
+/// expression and every problem found.
+pub fn check_program(program: &ASTNode, options: &Options) -> (TypeTable, Vec<Diagnostic>) {
+    let mut diagnostics: Vec<Diagnostic> = Resolver::new(options.warn_shadowing).resolve_program(program);
+    let (types, type_diagnostics) = TypeChecker::new().check_program(program);
+    diagnostics.extend(type_diagnostics);
+    flow::check_returns(program, &mut diagnostics);
+    flow::check_unreachable(program, &mut diagnostics);
+    (types, diagnostics)
+}
@@ -0,0 +1,44 @@
+use std::fs;
+use std::io;
+
+#[derive(Debug)]
+pub struct SourceFile {
+    pub name: String,
+    pub contents: String,
+}
+
+impl SourceFile {
+    /// Text of the given 1-based line, without its line terminator.
+    pub fn line(&self, line: u32) -> Option<&str> {
+        self.contents
+            .split('\n')
+            .nth(line.checked_sub(1)? as usize)
+            .map(|text| text.strip_suffix('\r').unwrap_or(text))
+    }
+}
+
+/// Every file seen by the compiler, indexed by the `file_id` stored in spans.
+#[derive(Debug, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        SourceMap { files: Vec::new() }
+    }
+
+    pub fn add_file(&mut self, name: &str, contents: String) -> u32 {
+        self.files.push(SourceFile { name: name.to_string(), contents });
+        (self.files.len() - 1) as u32
+    }
+
+    pub fn load_file(&mut self, path: &str) -> io::Result<u32> {
+        let contents = fs::read_to_string(path)?;
+        Ok(self.add_file(path, contents))
+    }
+
+    pub fn get(&self, file_id: u32) -> &SourceFile {
+        &self.files[file_id as usize]
+    }
+}
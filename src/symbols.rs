@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use crate::diagnostic::{self, Diagnostic};
+use crate::lexer::Span;
+use crate::parser::ASTNode;
+
+/// Stack of lexical scopes, innermost last. `T` is whatever a pass needs to
+/// remember about a name.
+pub struct SymbolTable<T> {
+    scopes: Vec<HashMap<String, T>>,
+}
+
+impl<T> SymbolTable<T> {
+    pub fn new() -> Self {
+        SymbolTable { scopes: vec![HashMap::new()] }
+    }
+
+    pub fn push_scope(&mut self) {
+        self.scopes.push(HashMap::new());
+    }
+
+    pub fn pop_scope(&mut self) {
+        self.scopes.pop();
+    }
+
+    /// Adds `name` to the innermost scope, returning the symbol it replaces
+    /// if the name was already declared in that same scope.
+    pub fn declare(&mut self, name: &str, symbol: T) -> Option<T> {
+        self.scopes.last_mut().expect("There is always a global scope").insert(name.to_string(), symbol)
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<&T> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name))
+    }
+
+    pub fn lookup_current(&self, name: &str) -> Option<&T> {
+        self.scopes.last().and_then(|scope| scope.get(name))
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum SymbolKind {
+    Function,
+    Parameter,
+    Variable,
+}
+
+impl SymbolKind {
+    fn declared_here(&self) -> &'static str {
+        match self {
+            SymbolKind::Function => "function declared here",
+            SymbolKind::Parameter => "parameter declared here",
+            SymbolKind::Variable => "previous declaration here",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Symbol {
+    kind: SymbolKind,
+    span: Span,
+}
+
+/// Name resolution: checks that every identifier refers to a declaration in
+/// an enclosing scope and that no scope declares the same name twice.
+/// Functions, blocks and loops each open a scope; parameters share the scope
+/// of the function's outermost block, as in C.
+pub struct Resolver {
+    symbols: SymbolTable<Symbol>,
+    warn_shadowing: bool,
+    // Set after a statement that failed to parse, until the end of its
+    // scope: it may have declared any name, so undeclared ones aren't
+    // reported there.
+    incomplete: bool,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Resolver {
+    pub fn new(warn_shadowing: bool) -> Self {
+        Resolver {
+            symbols: SymbolTable::new(),
+            warn_shadowing,
+            incomplete: false,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    pub fn resolve_program(mut self, program: &ASTNode) -> Vec<Diagnostic> {
+        // Functions are visible from the start of the file so that they can
+        // be called before (or from within) their own declaration.
+        if let ASTNode::Program(instructions) = program {
+            for instr in instructions {
+                if let ASTNode::FuncDec { name, span, .. } = instr {
+                    self.declare(name, SymbolKind::Function, *span);
+                }
+            }
+            for instr in instructions {
+                self.resolve(instr);
+            }
+        }
+        self.diagnostics
+    }
+
+    fn declare(&mut self, name: &str, kind: SymbolKind, span: Span) {
+        if let Some(previous) = self.symbols.lookup_current(name).copied() {
+            self.diagnostics.push(Diagnostic::error(
+                diagnostic::REDECLARATION,
+                format!("`{name}` is already declared in this scope"),
+                span,
+            ).with_label(previous.span, previous.kind.declared_here()));
+            return;
+        }
+        if self.warn_shadowing {
+            if let Some(previous) = self.symbols.lookup(name).copied() {
+                if previous.kind != SymbolKind::Function {
+                    self.diagnostics.push(Diagnostic::warning(
+                        diagnostic::SHADOWING,
+                        format!("Declaration of `{name}` shadows an outer declaration"),
+                        span,
+                    ).with_label(previous.span, previous.kind.declared_here()));
+                }
+            }
+        }
+        self.symbols.declare(name, Symbol { kind, span });
+    }
+
+    fn resolve_statements(&mut self, instructions: &[ASTNode]) {
+        for instr in instructions {
+            self.resolve(instr);
+        }
+    }
+
+    fn resolve(&mut self, node: &ASTNode) {
+        match node {
+            ASTNode::FuncDec { params, body, .. } => {
+                // Functions only see the globals, so names lost at the top
+                // level don't matter inside them.
+                let enclosing = std::mem::replace(&mut self.incomplete, false);
+                self.symbols.push_scope();
+                for (_, param_name, param_span) in params {
+                    self.declare(param_name, SymbolKind::Parameter, *param_span);
+                }
+                match body.as_ref() {
+                    ASTNode::Block(instructions, _) => self.resolve_statements(instructions),
+                    body => self.resolve(body),
+                }
+                self.symbols.pop_scope();
+                self.incomplete = enclosing;
+            }
+            ASTNode::Block(instructions, _) => {
+                let enclosing = self.incomplete;
+                self.symbols.push_scope();
+                self.resolve_statements(instructions);
+                self.symbols.pop_scope();
+                self.incomplete = enclosing;
+            }
+            ASTNode::VarDec { name, initializer, span, .. } => {
+                if let Some(initializer) = initializer {
+                    self.resolve(initializer);
+                }
+                self.declare(name, SymbolKind::Variable, *span);
+            }
+            ASTNode::Identifier(name, span) => {
+                match self.symbols.lookup(name) {
+                    None if self.incomplete => {}
+                    None => self.diagnostics.push(Diagnostic::error(
+                        diagnostic::UNDECLARED,
+                        format!("Use of undeclared identifier `{name}`"),
+                        *span,
+                    )),
+                    Some(symbol) if symbol.kind == SymbolKind::Function => self.diagnostics.push(Diagnostic::error(
+                        diagnostic::UNDECLARED,
+                        format!("`{name}` is a function, not a variable"),
+                        *span,
+                    ).with_label(symbol.span, "function declared here")),
+                    Some(_) => {}
+                }
+            }
+            ASTNode::Call { callee, args, span } => {
+                match self.symbols.lookup(callee) {
+                    None if self.incomplete => {}
+                    None => self.diagnostics.push(Diagnostic::error(
+                        diagnostic::UNDECLARED,
+                        format!("Call to undeclared function `{callee}`"),
+                        *span,
+                    )),
+                    Some(symbol) if symbol.kind != SymbolKind::Function => self.diagnostics.push(Diagnostic::error(
+                        diagnostic::UNDECLARED,
+                        format!("`{callee}` is not a function"),
+                        *span,
+                    ).with_label(symbol.span, "declared here")),
+                    Some(_) => {}
+                }
+                for arg in args {
+                    self.resolve(arg);
+                }
+            }
+            ASTNode::UnaryOP { operand, .. } | ASTNode::PostfixOP { operand, .. } => self.resolve(operand),
+            ASTNode::TernaryOP { condition, then_branch, else_branch, .. } => {
+                self.resolve(condition);
+                self.resolve(then_branch);
+                self.resolve(else_branch);
+            }
+            ASTNode::Index { array, index, .. } => {
+                self.resolve(array);
+                self.resolve(index);
+            }
+            ASTNode::ArrayLiteral(elements, _) => self.resolve_statements(elements),
+            ASTNode::BinaryOP { left, right, .. } => {
+                self.resolve(left);
+                self.resolve(right);
+            }
+            ASTNode::Assignment { left_term, right_term, .. } => {
+                self.resolve(left_term);
+                self.resolve(right_term);
+            }
+            ASTNode::ReturnStmt(value, _) => {
+                if let Some(value) = value {
+                    self.resolve(value);
+                }
+            }
+            ASTNode::IfStmt { condition, if_branch, else_branch, .. } => {
+                self.resolve(condition);
+                self.resolve(if_branch);
+                if let Some(else_branch) = else_branch {
+                    self.resolve(else_branch);
+                }
+            }
+            ASTNode::WhileStmt { condition, body, .. } => {
+                self.resolve(condition);
+                self.resolve(body);
+            }
+            ASTNode::DoWhileStmt { body, condition, .. } => {
+                self.resolve(body);
+                self.resolve(condition);
+            }
+            ASTNode::ForStmt { init, condition, step, body, .. } => {
+                self.symbols.push_scope();
+                for clause in [init, condition, step].into_iter().flatten() {
+                    self.resolve(clause);
+                }
+                self.resolve(body);
+                self.symbols.pop_scope();
+            }
+            ASTNode::Program(instructions) => self.resolve_statements(instructions),
+            ASTNode::IntLiteral(..)
+            | ASTNode::FloatLiteral(..)
+            | ASTNode::CharLiteral(..)
+            | ASTNode::StringLiteral(..) => {}
+            ASTNode::Error(_) => self.incomplete = true,
+        }
+    }
+}
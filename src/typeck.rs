@@ -0,0 +1,517 @@
+use std::collections::HashMap;
+use crate::diagnostic::{self, Diagnostic};
+use crate::lexer::{Span, TokType};
+use crate::parser::ASTNode;
+use crate::symbols::SymbolTable;
+use crate::types::Type;
+
+// Type of a function together with where it is declared, for the
+// "function defined here" label.
+struct Signature {
+    ty: Type,
+    span: Span,
+}
+
+/// Type of every expression, and of every declared variable (with the
+/// length of an unsized array filled in), keyed by the span of its node, for
+/// the passes that come after type checking.
+pub type TypeTable = HashMap<Span, Type>;
+
+// What the checker knows about a variable in scope.
+struct Variable {
+    ty: Type,
+    span: Span,
+    is_parameter: bool,
+}
+
+/// Assigns a type to every expression and checks initialisers, assignments,
+/// operator operands, call arguments and return values against it.
+pub struct TypeChecker {
+    symbols: SymbolTable<Variable>,
+    functions: HashMap<String, Signature>,
+    ret_type: Type,
+    types: TypeTable,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl TypeChecker {
+    pub fn new() -> Self {
+        TypeChecker {
+            symbols: SymbolTable::new(),
+            functions: HashMap::new(),
+            ret_type: Type::Void,
+            types: TypeTable::new(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    pub fn check_program(mut self, program: &ASTNode) -> (TypeTable, Vec<Diagnostic>) {
+        if let ASTNode::Program(instructions) = program {
+            for instr in instructions {
+                if let ASTNode::FuncDec { name, params, ret_type, span, .. } = instr {
+                    let params = params.iter().map(|(param_type, ..)| param_type.clone()).collect();
+                    let ty = Type::Function { params, ret_type: Box::new(ret_type.clone()) };
+                    self.functions.insert(name.clone(), Signature { ty, span: *span });
+                }
+            }
+            for instr in instructions {
+                self.check_stmt(instr);
+            }
+        }
+        (self.types, self.diagnostics)
+    }
+
+    fn mismatch(&mut self, expected: &Type, found: &Type, span: Span) {
+        self.diagnostics.push(Diagnostic::error(
+            diagnostic::TYPE_MISMATCH,
+            format!("Mismatched types: expected {expected}, found {found}"),
+            span,
+        ));
+    }
+
+    fn expect_type(&mut self, expected: &Type, node: &ASTNode) {
+        let found = self.check_expr(node);
+        if !expected.accepts(&found) {
+            self.mismatch(expected, &found, node.span());
+        }
+    }
+
+    // Conditions of if/while/do/for must be numbers, as in C.
+    fn check_condition(&mut self, condition: &ASTNode) {
+        let found = self.check_expr(condition);
+        if !found.decayed().is_scalar() {
+            self.diagnostics.push(Diagnostic::error(
+                diagnostic::TYPE_MISMATCH,
+                format!("A condition must be a number or a pointer, found {found}"),
+                condition.span(),
+            ));
+        }
+    }
+
+    // Checks an initialiser against the declared type and returns the type
+    // the variable ends up with: the length of an unsized array, as in
+    // `int a[] = {1, 2}`, comes from its initialiser list.
+    fn check_initializer(&mut self, expected: &Type, node: &ASTNode) -> Type {
+        match (expected.unqualified(), node) {
+            (Type::Array(element, len), ASTNode::ArrayLiteral(elements, span)) => {
+                for elem in elements {
+                    self.check_initializer(element, elem);
+                }
+                if let Some(len) = len.filter(|len| elements.len() > *len) {
+                    self.diagnostics.push(Diagnostic::error(
+                        diagnostic::TYPE_MISMATCH,
+                        format!("Too many elements for an array of length {len}, found {}", elements.len()),
+                        *span,
+                    ));
+                }
+                let ty = Type::Array(element.clone(), Some(len.unwrap_or(elements.len())));
+                let ty = match expected {
+                    Type::Const(_) => Type::Const(Box::new(ty)),
+                    _ => ty,
+                };
+                self.types.insert(*span, ty.clone());
+                ty
+            }
+            (Type::Array(..), _) => {
+                self.check_expr(node);
+                self.diagnostics.push(Diagnostic::error(
+                    diagnostic::TYPE_MISMATCH,
+                    "An array must be initialised with a list in braces".to_string(),
+                    node.span(),
+                ));
+                expected.clone()
+            }
+            _ => {
+                self.expect_type(expected, node);
+                expected.clone()
+            }
+        }
+    }
+
+    fn check_stmt(&mut self, node: &ASTNode) {
+        match node {
+            ASTNode::FuncDec { params, ret_type, body, .. } => {
+                let enclosing_ret_type = std::mem::replace(&mut self.ret_type, ret_type.clone());
+                self.symbols.push_scope();
+                for (param_type, param_name, param_span) in params {
+                    self.symbols.declare(param_name, Variable { ty: param_type.clone(), span: *param_span, is_parameter: true });
+                }
+                self.check_stmt(body);
+                self.symbols.pop_scope();
+                self.ret_type = enclosing_ret_type;
+            }
+            ASTNode::Program(instructions) | ASTNode::Block(instructions, _) => {
+                self.symbols.push_scope();
+                for instr in instructions {
+                    self.check_stmt(instr);
+                }
+                self.symbols.pop_scope();
+            }
+            ASTNode::VarDec { var_type, name, initializer, span } => {
+                let var_type = match initializer {
+                    Some(initializer) => self.check_initializer(var_type, initializer),
+                    None => var_type.clone(),
+                };
+                self.types.insert(*span, var_type.clone());
+                self.symbols.declare(name, Variable { ty: var_type, span: *span, is_parameter: false });
+            }
+            ASTNode::ReturnStmt(value, span) => match value {
+                Some(value) if self.ret_type == Type::Void => {
+                    self.check_expr(value);
+                    self.diagnostics.push(Diagnostic::error(
+                        diagnostic::TYPE_MISMATCH,
+                        "A void function cannot return a value".to_string(),
+                        value.span(),
+                    ));
+                }
+                Some(value) => {
+                    let ret_type = self.ret_type.clone();
+                    self.expect_type(&ret_type, value);
+                }
+                None if self.ret_type != Type::Void => {
+                    self.diagnostics.push(Diagnostic::error(
+                        diagnostic::TYPE_MISMATCH,
+                        format!("Missing return value of type {}", self.ret_type),
+                        *span,
+                    ));
+                }
+                None => {}
+            },
+            ASTNode::IfStmt { condition, if_branch, else_branch, .. } => {
+                self.check_condition(condition);
+                self.check_stmt(if_branch);
+                if let Some(else_branch) = else_branch {
+                    self.check_stmt(else_branch);
+                }
+            }
+            ASTNode::WhileStmt { condition, body, .. } | ASTNode::DoWhileStmt { body, condition, .. } => {
+                self.check_condition(condition);
+                self.check_stmt(body);
+            }
+            ASTNode::ForStmt { init, condition, step, body, .. } => {
+                self.symbols.push_scope();
+                if let Some(init) = init {
+                    self.check_stmt(init);
+                }
+                if let Some(condition) = condition {
+                    self.check_condition(condition);
+                }
+                if let Some(step) = step {
+                    self.check_expr(step);
+                }
+                self.check_stmt(body);
+                self.symbols.pop_scope();
+            }
+            ASTNode::Error(_) => {}
+            expression => {
+                self.check_expr(expression);
+            }
+        }
+    }
+
+    fn check_expr(&mut self, node: &ASTNode) -> Type {
+        let ty = self.infer_expr(node);
+        self.types.insert(node.span(), ty.clone());
+        ty
+    }
+
+    fn infer_expr(&mut self, node: &ASTNode) -> Type {
+        match node {
+            ASTNode::IntLiteral(..) => Type::Int,
+            ASTNode::FloatLiteral(..) => Type::Float,
+            ASTNode::CharLiteral(..) => Type::Char,
+            ASTNode::StringLiteral(..) => Type::String,
+            // Undeclared names were already reported by the resolver.
+            ASTNode::Identifier(name, _) => self.symbols.lookup(name).map_or(Type::Error, |variable| variable.ty.clone()),
+            ASTNode::UnaryOP { operator, operand, span } => {
+                let operand_type = self.check_expr(operand);
+                if operator.as_operator().is_some_and(|op| op == "&") {
+                    if !operand.is_lvalue() {
+                        self.diagnostics.push(Diagnostic::error(
+                            diagnostic::TYPE_MISMATCH,
+                            "Cannot take the address of a value that is not stored in a variable".to_string(),
+                            operand.span(),
+                        ));
+                        return Type::Error;
+                    }
+                    return Type::Pointer(Box::new(operand_type));
+                }
+                if matches!(operator.as_operator().map(String::as_str), Some("++" | "--")) {
+                    self.check_assignable(operand, &operand_type);
+                }
+                self.check_unary(operator, &operand_type, *span)
+            }
+            ASTNode::PostfixOP { operator, operand, span } => {
+                let operand_type = self.check_expr(operand);
+                self.check_assignable(operand, &operand_type);
+                self.check_unary(operator, &operand_type, *span)
+            }
+            ASTNode::BinaryOP { operator, left, right, span } => {
+                let left_type = self.check_expr(left);
+                let right_type = self.check_expr(right);
+                self.check_binary(operator.as_operator().map_or("", |op| op.as_str()), &left_type, &right_type, *span)
+            }
+            ASTNode::Assignment { operator, left_term, right_term, span } => {
+                let left_type = self.check_expr(left_term);
+                let right_type = self.check_expr(right_term);
+                if let Type::Array(..) = left_type.unqualified() {
+                    self.diagnostics.push(Diagnostic::error(
+                        diagnostic::TYPE_MISMATCH,
+                        format!("Cannot assign to an array of type {left_type}, assign its elements instead"),
+                        left_term.span(),
+                    ));
+                    return Type::Error;
+                }
+                self.check_assignable(left_term, &left_type);
+                let op = operator.as_operator().map_or("=", |op| op.as_str());
+                // `a += b` is checked as `a = a + b`.
+                let value_type = match op.strip_suffix('=') {
+                    Some(binary) if !binary.is_empty() => self.check_binary(binary, &left_type, &right_type, *span),
+                    _ => right_type,
+                };
+                if !left_type.accepts(&value_type) {
+                    self.mismatch(&left_type, &value_type, right_term.span());
+                }
+                left_type
+            }
+            ASTNode::TernaryOP { condition, then_branch, else_branch, span } => {
+                self.check_condition(condition);
+                let then_type = self.check_expr(then_branch);
+                let else_type = self.check_expr(else_branch);
+                let (then_value, else_value) = (then_type.decayed(), else_type.decayed());
+                if then_value.is_arithmetic() && else_value.is_arithmetic() {
+                    then_value.arithmetic_result(&else_value)
+                } else if then_value.accepts(&else_value) {
+                    then_value
+                } else if else_value.accepts(&then_value) {
+                    else_value
+                } else {
+                    self.diagnostics.push(Diagnostic::error(
+                        diagnostic::TYPE_MISMATCH,
+                        format!("The branches of `?:` have different types: {then_type} and {else_type}"),
+                        *span,
+                    ));
+                    Type::Error
+                }
+            }
+            ASTNode::Index { array, index, span } => {
+                let array_type = self.check_expr(array);
+                let index_type = self.check_expr(index);
+                if !index_type.is_integral() {
+                    self.diagnostics.push(Diagnostic::error(
+                        diagnostic::TYPE_MISMATCH,
+                        format!("An array index must be an integer, found {index_type}"),
+                        index.span(),
+                    ));
+                }
+                match array_type.unqualified() {
+                    // The elements of a const array are const too.
+                    Type::Array(element, _) if matches!(array_type, Type::Const(_)) => Type::Const(element.clone()),
+                    Type::Array(element, _) | Type::Pointer(element) => *element.clone(),
+                    Type::Error => Type::Error,
+                    _ => {
+                        self.diagnostics.push(Diagnostic::error(
+                            diagnostic::TYPE_MISMATCH,
+                            format!("Cannot index a value of type {array_type}"),
+                            *span,
+                        ));
+                        Type::Error
+                    }
+                }
+            }
+            // Only initialisers are parsed as lists, and `check_initializer`
+            // takes care of the ones that initialise an array.
+            ASTNode::ArrayLiteral(_, span) => {
+                self.diagnostics.push(Diagnostic::error(
+                    diagnostic::TYPE_MISMATCH,
+                    "A list in braces can only initialise an array".to_string(),
+                    *span,
+                ));
+                Type::Error
+            }
+            ASTNode::Call { callee, args, span } => self.check_call(callee, args, *span),
+            ASTNode::Error(_) => Type::Error,
+            statement => {
+                self.check_stmt(statement);
+                Type::Void
+            }
+        }
+    }
+
+    // Reports an assignment, `++` or `--` whose target is const. When the
+    // target is (an element of) a const variable, the declaration of the
+    // variable is labelled.
+    fn check_assignable(&mut self, target: &ASTNode, target_type: &Type) {
+        if !matches!(target_type, Type::Const(_)) {
+            return;
+        }
+        let mut root = target;
+        while let ASTNode::Index { array, .. } = root {
+            root = array;
+        }
+        let variable = match root {
+            ASTNode::Identifier(name, _) => self.symbols.lookup(name)
+                .filter(|variable| matches!(variable.ty, Type::Const(_)))
+                .map(|variable| (name, variable)),
+            _ => None,
+        };
+        let diagnostic = match variable {
+            Some((name, variable)) => Diagnostic::error(
+                diagnostic::ASSIGN_TO_CONST,
+                format!("Cannot assign to `{name}`, it is declared const"),
+                target.span(),
+            ).with_label(variable.span, &if variable.is_parameter {
+                format!("`{name}` declared as a const parameter here")
+            } else {
+                format!("`{name}` declared const here")
+            }),
+            None => Diagnostic::error(
+                diagnostic::ASSIGN_TO_CONST,
+                format!("Cannot assign to a value of type {target_type}"),
+                target.span(),
+            ),
+        };
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn check_unary(&mut self, operator: &TokType, operand: &Type, span: Span) -> Type {
+        let op = operator.as_operator().map_or("", |op| op.as_str());
+        let (valid, result) = match op {
+            "-" | "+" => (operand.is_arithmetic(), operand.arithmetic_result(&Type::Int)),
+            "!" => (operand.decayed().is_scalar(), Type::Int),
+            "~" => (operand.is_integral(), Type::Int),
+            "++" | "--" => (operand.is_scalar(), operand.unqualified().clone()),
+            "*" => match operand.decayed() {
+                Type::Pointer(pointee) => (true, *pointee),
+                Type::Error => (true, Type::Error),
+                _ => (false, Type::Error),
+            },
+            _ => (false, Type::Error),
+        };
+        if !valid {
+            self.diagnostics.push(Diagnostic::error(
+                diagnostic::TYPE_MISMATCH,
+                format!("Operator `{op}` cannot be applied to {operand}"),
+                span,
+            ));
+            return Type::Error;
+        }
+        result
+    }
+
+    // Arrays decay to pointers here. Adding an integer to a pointer moves it
+    // by that many elements and subtracting two pointers to the same type
+    // counts the elements between them; the scaling by the element size is
+    // left to code generation.
+    fn check_binary(&mut self, op: &str, left_type: &Type, right_type: &Type, span: Span) -> Type {
+        let (left, right) = (left_type.decayed(), right_type.decayed());
+        let is_pointer = |ty: &Type| matches!(ty, Type::Pointer(_));
+        let (valid, result) = match op {
+            "+" if is_pointer(&left) => (right.is_integral(), left.clone()),
+            "+" if is_pointer(&right) => (left.is_integral(), right.clone()),
+            "-" if is_pointer(&left) && is_pointer(&right) => (left == right, Type::Int),
+            "-" if is_pointer(&left) => (right.is_integral(), left.clone()),
+            "+" | "-" | "*" | "/" => (left.is_arithmetic() && right.is_arithmetic(), left.arithmetic_result(&right)),
+            "%" | "&" | "|" | "^" | "<<" | ">>" => (left.is_integral() && right.is_integral(), Type::Int),
+            "<" | ">" | "<=" | ">=" => (
+                (left.is_arithmetic() && right.is_arithmetic()) || (is_pointer(&left) && left == right),
+                Type::Int,
+            ),
+            "&&" | "||" => (left.is_scalar() && right.is_scalar(), Type::Int),
+            "==" | "!=" => (
+                (left.is_arithmetic() && right.is_arithmetic()) || left.accepts(&right) || right.accepts(&left),
+                Type::Int,
+            ),
+            _ => (false, Type::Error),
+        };
+        if !valid {
+            self.diagnostics.push(Diagnostic::error(
+                diagnostic::TYPE_MISMATCH,
+                format!("Operator `{op}` cannot be applied to {left_type} and {right_type}"),
+                span,
+            ));
+            return Type::Error;
+        }
+        result
+    }
+
+    fn check_call(&mut self, callee: &str, args: &[ASTNode], span: Span) -> Type {
+        let arg_types: Vec<Type> = args.iter().map(|arg| self.check_expr(arg)).collect();
+        let Some(Signature { ty: Type::Function { params, ret_type }, span: decl_span }) = self.functions.get(callee) else {
+            return Type::Error;
+        };
+        let (params, ret_type, decl_span) = (params.clone(), *ret_type.clone(), *decl_span);
+        if params.len() != args.len() {
+            self.diagnostics.push(Diagnostic::error(
+                diagnostic::ARGUMENT_COUNT,
+                format!("Function `{callee}` takes {} argument(s) but {} were supplied", params.len(), args.len()),
+                span,
+            ).with_label(decl_span, "function defined here"));
+            return ret_type;
+        }
+        for ((param, found), arg) in params.iter().zip(arg_types).zip(args) {
+            if !param.accepts(&found) {
+                self.mismatch(param, &found, arg.span());
+            }
+        }
+        ret_type
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{Lexer, Options};
+    use crate::parser::parse_program;
+
+    // Codes of the type errors reported for `body`, the body of a function
+    // returning int.
+    fn check(body: &str) -> Vec<&'static str> {
+        let source = format!("fn f() -> int {{ {body} return 0; }}");
+        let (program, diagnostics) = parse_program(Lexer::new(&source, 0, &Options::default()));
+        assert!(diagnostics.is_empty(), "{diagnostics:?}");
+        let (_, diagnostics) = TypeChecker::new().check_program(&program);
+        diagnostics.iter().map(|diagnostic| diagnostic.code).collect()
+    }
+
+    #[test]
+    fn widens_without_losing_information() {
+        assert!(check("char c = 'a'; int i = c; float x = i; float y = c;").is_empty());
+        assert!(check("float x = 1 + 2.5 * 'a';").is_empty());
+    }
+
+    #[test]
+    fn rejects_narrowing() {
+        assert_eq!(check("float x = 1.5; int i = x;"), [diagnostic::TYPE_MISMATCH]);
+        assert_eq!(check("int i = 1; char c = i;"), [diagnostic::TYPE_MISMATCH]);
+        assert_eq!(check("string s = 1;"), [diagnostic::TYPE_MISMATCH]);
+    }
+
+    #[test]
+    fn decays_arrays_to_pointers() {
+        assert!(check("int a[3]; int p* = a; int x = *(a + 1) + p[2];").is_empty());
+        assert_eq!(check("int a[3]; float p* = a;"), [diagnostic::TYPE_MISMATCH]);
+        assert_eq!(check("int a[3]; int b[3]; a = b;"), [diagnostic::TYPE_MISMATCH]);
+    }
+
+    #[test]
+    fn unifies_conditional_branches() {
+        assert!(check("float x = 1 ? 2 : 3.5;").is_empty());
+        assert_eq!(check("int x = 1 ? 2 : 3.5;"), [diagnostic::TYPE_MISMATCH]);
+        assert_eq!(check("int x = 1 ? 2 : \"s\";"), [diagnostic::TYPE_MISMATCH]);
+    }
+
+    #[test]
+    fn keeps_const_behind_pointers() {
+        assert_eq!(check("const int v = 1; int p* = &v;"), [diagnostic::TYPE_MISMATCH]);
+        assert_eq!(check("const int v = 1; *&v = 2;"), [diagnostic::ASSIGN_TO_CONST]);
+    }
+
+    #[test]
+    fn rejects_assignments_to_const() {
+        assert_eq!(check("const int c = 1; c = 2;"), [diagnostic::ASSIGN_TO_CONST]);
+        assert_eq!(check("const int a[2] = {1, 2}; a[0] = 3;"), [diagnostic::ASSIGN_TO_CONST]);
+        // `const int p*` is a constant pointer to a modifiable int.
+        assert_eq!(check("int v = 1; const int p* = &v; *p = 2; p = &v;"), [diagnostic::ASSIGN_TO_CONST]);
+    }
+}
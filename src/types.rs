@@ -0,0 +1,128 @@
+use std::fmt;
+
+/// Types of the language described in `lang.txt`. `Error` is given to
+/// expressions that already produced a diagnostic, and is compatible with
+/// everything so that one mistake isn't reported over and over.
+#[derive(PartialEq, Debug, Clone)]
+pub enum Type {
+    Int,
+    Float,
+    Char,
+    String,
+    Void,
+    Pointer(Box<Type>),
+    // `int a[]` has no length, `int a[3]` has one.
+    Array(Box<Type>, Option<usize>),
+    Function {
+        params: Vec<Type>,
+        ret_type: Box<Type>,
+    },
+    Const(Box<Type>),
+    Error,
+}
+
+impl Type {
+    /// Primitive type named by a keyword, if any.
+    pub fn from_name(name: &str) -> Option<Type> {
+        match name {
+            "int" => Some(Type::Int),
+            "float" => Some(Type::Float),
+            "char" => Some(Type::Char),
+            "string" => Some(Type::String),
+            "void" => Some(Type::Void),
+            _ => None,
+        }
+    }
+
+    /// The type with any top-level `const` removed.
+    pub fn unqualified(&self) -> &Type {
+        match self {
+            Type::Const(inner) => inner.unqualified(),
+            ty => ty,
+        }
+    }
+
+    pub fn is_integral(&self) -> bool {
+        matches!(self.unqualified(), Type::Int | Type::Char | Type::Error)
+    }
+
+    pub fn is_arithmetic(&self) -> bool {
+        matches!(self.unqualified(), Type::Int | Type::Float | Type::Char | Type::Error)
+    }
+
+    /// Pointers, or numbers: what can be used as a condition.
+    pub fn is_scalar(&self) -> bool {
+        self.is_arithmetic() || matches!(self.unqualified(), Type::Pointer(_))
+    }
+
+    /// Arrays used as values decay to a pointer to their first element, as
+    /// in C. Other types are returned without qualifiers.
+    pub fn decayed(&self) -> Type {
+        match self.unqualified() {
+            Type::Array(element, _) => Type::Pointer(element.clone()),
+            ty => ty.clone(),
+        }
+    }
+
+    /// Implicit conversions: a value of type `from` can be used where `self`
+    /// is expected only if no information is lost, i.e. char -> int,
+    /// char -> float and int -> float. Everything else needs the same type.
+    /// Qualifiers don't matter here: reading a const value is always fine.
+    /// An array without a length, like an `int a[]` parameter, accepts
+    /// arrays of any length, and a pointer accepts an array of its pointee.
+    /// A pointer to const accepts a pointer to the same non-const type.
+    pub fn accepts(&self, from: &Type) -> bool {
+        let (to, from) = (self.unqualified(), from.unqualified());
+        match (to, from) {
+            (Type::Error, _) | (_, Type::Error) => true,
+            (Type::Array(to_element, None), Type::Array(from_element, _)) => to_element == from_element,
+            (Type::Pointer(pointee), Type::Array(element, _)) => pointee == element,
+            (Type::Pointer(to_pointee), Type::Pointer(from_pointee)) => {
+                to_pointee == from_pointee
+                    || (matches!(**to_pointee, Type::Const(_)) && to_pointee.unqualified() == from_pointee.as_ref())
+            }
+            (Type::Int, Type::Char) | (Type::Float, Type::Char) | (Type::Float, Type::Int) => true,
+            _ => to == from,
+        }
+    }
+
+    /// Result of the usual arithmetic conversions between two operands:
+    /// float if either side is float, int otherwise (char is promoted).
+    pub fn arithmetic_result(&self, other: &Type) -> Type {
+        match (self.unqualified(), other.unqualified()) {
+            (Type::Error, _) | (_, Type::Error) => Type::Error,
+            (Type::Float, _) | (_, Type::Float) => Type::Float,
+            _ => Type::Int,
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Float => write!(f, "float"),
+            Type::Char => write!(f, "char"),
+            Type::String => write!(f, "string"),
+            Type::Void => write!(f, "void"),
+            Type::Pointer(pointee) => write!(f, "{pointee}*"),
+            Type::Array(..) => {
+                // `int[2][3]` is an array of 2 arrays of 3 ints, so the
+                // outermost length is printed first.
+                let mut dims = String::new();
+                let mut element = self;
+                while let Type::Array(inner, len) = element {
+                    dims.push_str(&len.map_or("[]".to_string(), |len| format!("[{len}]")));
+                    element = inner;
+                }
+                write!(f, "{element}{dims}")
+            }
+            Type::Function { params, ret_type } => {
+                let params: Vec<String> = params.iter().map(Type::to_string).collect();
+                write!(f, "fn({}) -> {ret_type}", params.join(", "))
+            }
+            Type::Const(inner) => write!(f, "const {inner}"),
+            Type::Error => write!(f, "{{error}}"),
+        }
+    }
+}